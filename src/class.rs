@@ -1,67 +1,622 @@
 use core::convert::TryFrom;
 
-use crate::pipe::Requester;
+use crate::pipe::{check_card_issuers_data, EscapeHandler, PacketTap, Requester};
 use embedded_time::duration::Extensions;
 
 use crate::{
     constants::*,
-    pipe::Pipe,
-    types::{packet::RawPacket, ClassRequest, Status},
+    pipe::{Pipe, PipeStats, PollResult, State},
+    types::{
+        packet::RawPacket, CardEvent, CcidError, ClassRequest, ConfigError, IccStatus, Parameters,
+        Protocol, ResetReason, SeqPolicy, Status, T1Parameters,
+    },
 };
 
 use usb_device::class_prelude::*;
 type Result<T> = core::result::Result<T, UsbError>;
 
-pub struct Ccid<'bus, 'pipe, Bus, const N: usize>
+/// [`Ccid`] with an interchange sized for short APDUs ([`SHORT_APDU_LEN`] = 261 bytes).
+///
+/// The interchange channel then needs about 261 bytes of RAM, and the buffers reassembling
+/// CCID messages and holding Escape responses 271 bytes each (512 with `highspeed-usb`),
+/// which is also the advertised dwMaxCCIDMessageLength.  Longer command APDUs are rejected with SW 6700.  Construct it with
+/// [`CcidBuilder::build`] or [`Ccid::new_with_message_length`].
+pub type ShortApduCcid<'bus, 'pipe, Bus> = Ccid<'bus, 'pipe, Bus, SHORT_APDU_LEN, SHORT_MSG_LENGTH>;
+
+/// [`Ccid`] with an interchange sized for the largest APDU that fits into one CCID message
+/// ([`EXTENDED_APDU_LEN`] = 3062 bytes).
+///
+/// The interchange channel, the buffer reassembling CCID messages and the one holding Escape
+/// responses then need about 3 KiB of RAM each.
+pub type ExtendedApduCcid<'bus, 'pipe, Bus> = Ccid<'bus, 'pipe, Bus, EXTENDED_APDU_LEN>;
+
+/// Configuration of a [`Ccid`] that is fixed at construction.
+///
+/// Invalid values are rejected by [`build`](Self::build) with a [`ConfigError`].
+#[derive(Copy, Clone, Debug)]
+pub struct CcidBuilder<'a> {
+    card_issuers_data: Option<&'a [u8]>,
+    protocol: Protocol,
+    max_message_length: Option<usize>,
+    wait_extension_multiplier: u8,
+    t1_parameters: T1Parameters,
+    fast_timing: bool,
+    parameters_require_power: bool,
+    features: Option<u32>,
+    bcd_ccid: u16,
+    pin_support: u8,
+    interrupt: bool,
+}
+
+impl Default for CcidBuilder<'_> {
+    fn default() -> Self {
+        Self {
+            card_issuers_data: None,
+            protocol: Protocol::default(),
+            max_message_length: None,
+            wait_extension_multiplier: 1,
+            t1_parameters: T1Parameters::default(),
+            fast_timing: false,
+            parameters_require_power: false,
+            features: None,
+            bcd_ccid: BCD_CCID,
+            pin_support: PIN_SUPPORT,
+            interrupt: false,
+        }
+    }
+}
+
+impl<'a> CcidBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Card issuer's data of at most 13 bytes, personalizing the Answer-to-Reset, for instance
+    /// by ASCII-encoding vendor or model information.
+    pub fn card_issuers_data(mut self, card_issuers_data: &'a [u8]) -> Self {
+        self.card_issuers_data = Some(card_issuers_data);
+        self
+    }
+
+    /// The protocol announced in the ATR and the functional descriptor, and reported by
+    /// GetParameters (default: [`Protocol::T1`]).
+    ///
+    /// Use T=0 only if legacy middleware requires it.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// The advertised dwMaxCCIDMessageLength (default: the `MSG` buffer size of the class).
+    ///
+    /// It must be at least the packet size and at most `MSG`.
+    pub fn max_message_length(mut self, max_message_length: usize) -> Self {
+        self.max_message_length = Some(max_message_length);
+        self
+    }
+
+    /// The BWT multiplier requested by each time extension while the application is
+    /// processing a command (default: 1), see [`Ccid::set_wait_extension_multiplier`].
+    pub fn wait_extension_multiplier(mut self, multiplier: u8) -> Self {
+        self.wait_extension_multiplier = multiplier;
+        self
+    }
+
+    /// The T=1 parameters reported by GetParameters until the host sets others, e.g. to
+    /// advertise a different Fi/Di, guard time or BWI/CWI (default: [`T1Parameters::default`]).
+    ///
+    /// Ignored if the protocol is T=0.
+    pub fn t1_parameters(mut self, parameters: T1Parameters) -> Self {
+        self.t1_parameters = parameters;
+        self
+    }
+
+    /// Announce Fi = 512 and Di = 32 in the ATR and report them in the bmFindexDindex of the
+    /// protocol parameters (default: no), see
+    /// [`AtrBuilder::fast_timing`][crate::AtrBuilder::fast_timing].
+    ///
+    /// Not all readers support this rate.  Overrides the Fi/Di of the
+    /// [`t1_parameters`](Self::t1_parameters).
+    pub fn fast_timing(mut self, fast_timing: bool) -> Self {
+        self.fast_timing = fast_timing;
+        self
+    }
+
+    /// Fail GetParameters with `IccMute` until the host powered on the ICC (default: no), see
+    /// [`Ccid::set_parameters_require_power`].
+    ///
+    /// By default, the default parameters are returned at any time, as some host stacks query
+    /// them during enumeration.
+    pub fn parameters_require_power(mut self, require: bool) -> Self {
+        self.parameters_require_power = require;
+        self
+    }
+
+    /// The dwFeatures reported in the functional descriptor (default: `0x0004_0840`, i.e.
+    /// automatic ICC voltage selection and IFSD exchange with extended APDU level exchange,
+    /// or short APDU level exchange if `N` is at most [`SHORT_APDU_LEN`]).
+    ///
+    /// As only APDU level exchange is implemented, the features must select short or extended
+    /// APDU level exchange, see [`Ccid::set_features`].
+    pub fn features(mut self, features: u32) -> Self {
+        self.features = Some(features);
+        self
+    }
+
+    /// The bcdCCID reported in the functional descriptor (default: `0x0110`, revision 1.10).
+    pub fn bcd_ccid(mut self, bcd_ccid: u16) -> Self {
+        self.bcd_ccid = bcd_ccid;
+        self
+    }
+
+    /// The bPINSupport reported in the functional descriptor (default: 0, no PIN pad).
+    ///
+    /// Set 0x01 for PIN verification and 0x02 for PIN modification if the application
+    /// collects PINs on-device: hosts then send PC_to_RDR_Secure for these operations, which
+    /// are forwarded to the application, see [`Ccid::is_secure_command`].  Secure messages
+    /// for operations not advertised here are rejected.
+    pub fn pin_support(mut self, pin_support: u8) -> Self {
+        self.pin_support = pin_support;
+        self
+    }
+
+    /// Allocate an interrupt IN endpoint for RDR_to_PC_NotifySlotChange and
+    /// RDR_to_PC_HardwareError messages (default: no).
+    ///
+    /// See [`Ccid::notify_slot_change`] and [`Ccid::on_card_event`].  Not all peripherals have an endpoint to spare.
+    pub fn interrupt(mut self, interrupt: bool) -> Self {
+        self.interrupt = interrupt;
+        self
+    }
+
+    /// Validate the configuration and construct the class.
+    pub fn build<'bus, 'pipe, Bus, const N: usize, const MSG: usize>(
+        self,
+        allocator: &'bus UsbBusAllocator<Bus>,
+        request_pipe: Requester<'pipe, N>,
+    ) -> core::result::Result<Ccid<'bus, 'pipe, Bus, N, MSG>, ConfigError>
+    where
+        Bus: 'static + UsbBus,
+    {
+        check_card_issuers_data(self.card_issuers_data)?;
+        // extended APDUs are only worth advertising if the interchange can hold one
+        let features = self.features.unwrap_or(if N > SHORT_APDU_LEN {
+            FEATURES
+        } else {
+            SHORT_APDU_FEATURES
+        });
+        check_features(features, N)?;
+        let max_message_length = self.max_message_length.unwrap_or(MSG);
+        if !(PACKET_SIZE..=MSG).contains(&max_message_length) {
+            return Err(ConfigError::UnsupportedMessageLength);
+        }
+        if self.pin_support & !(PIN_SUPPORT_VERIFICATION | PIN_SUPPORT_MODIFICATION) != 0 {
+            return Err(ConfigError::UnsupportedPinSupport);
+        }
+
+        let read = allocator.bulk(PACKET_SIZE as _);
+        let write = allocator.bulk(PACKET_SIZE as _);
+        let interrupt = self
+            .interrupt
+            .then(|| allocator.interrupt(INTERRUPT_PACKET_SIZE, INTERRUPT_INTERVAL_MS));
+        let mut pipe = Pipe::new(
+            write,
+            interrupt,
+            request_pipe,
+            self.card_issuers_data,
+            self.protocol,
+            max_message_length,
+            self.wait_extension_multiplier
+                .clamp(1, MAX_WAIT_EXTENSION_MULTIPLIER),
+        );
+        pipe.set_t1_parameters(self.t1_parameters);
+        if self.fast_timing {
+            pipe.set_fast_timing(self.card_issuers_data);
+        }
+        pipe.set_pin_support(self.pin_support);
+        pipe.set_parameters_require_power(self.parameters_require_power);
+        let interface_number = allocator.interface();
+        let string_index = allocator.string();
+        Ok(Ccid {
+            interface_number,
+            string_index,
+            read,
+            features,
+            bcd_ccid: self.bcd_ccid,
+            pipe,
+        })
+    }
+}
+
+// Only APDU level exchange is implemented, see `handle_transfer`, and extended APDUs need an
+// interchange larger than a short APDU.
+fn check_features(features: u32, apdu_len: usize) -> core::result::Result<(), ConfigError> {
+    match features & FEATURES_EXCHANGE_LEVEL_MASK {
+        FEATURES_SHORT_APDU => Ok(()),
+        FEATURES_EXTENDED_APDU if apdu_len > SHORT_APDU_LEN => Ok(()),
+        _ => Err(ConfigError::UnsupportedFeatures),
+    }
+}
+
+/// CCID class, forwarding command APDUs of up to `N` bytes to the application.
+///
+/// The application answers each command APDU with the response APDU over the interchange.
+/// If it cannot build a response, e.g. because of a flash read error, it can instead respond
+/// with a single byte, which is sent to the host as the CCID bError of a failed DataBlock,
+/// such as `0xFD` (XFR_PARITY_ERROR).
+///
+/// `MSG` is the size of the buffer used to reassemble CCID messages spanning several USB
+/// packets, and the default dwMaxCCIDMessageLength.  Use [`CcidBuilder`] to configure the class.
+pub struct Ccid<'bus, 'pipe, Bus, const N: usize, const MSG: usize = MAX_MSG_LENGTH>
 where
     Bus: 'static + UsbBus,
 {
     interface_number: InterfaceNumber,
     string_index: StringIndex,
     read: EndpointOut<'bus, Bus>,
-    // interrupt: EndpointIn<'static, Bus>,
-    pipe: Pipe<'bus, 'pipe, Bus, N>,
+    features: u32,
+    bcd_ccid: u16,
+    pipe: Pipe<'bus, 'pipe, Bus, N, MSG>,
 }
 
 impl<'bus, 'pipe, Bus, const N: usize> Ccid<'bus, 'pipe, Bus, N>
 where
     Bus: 'static + UsbBus,
 {
-    /// Class constructor.
+    /// Class constructor announcing T=1, with the default dwMaxCCIDMessageLength.
     ///
-    /// The optional card issuer's data may be of length at most 13 bytes,
-    /// and allows personalizing the Answer-to-Reset, for instance by
-    /// ASCII-encoding vendor or model information.
+    /// Use [`CcidBuilder`] to select another protocol.  Panics if the card issuer's data is too
+    /// long, see [`try_new`](Self::try_new).
     pub fn new(
         allocator: &'bus UsbBusAllocator<Bus>,
         request_pipe: Requester<'pipe, N>,
         card_issuers_data: Option<&[u8]>,
     ) -> Self {
-        let read = allocator.bulk(PACKET_SIZE as _);
-        let write = allocator.bulk(PACKET_SIZE as _);
-        // TODO: Add interrupt endpoint, so PC/SC does not
-        // constantly poll us with GetSlotStatus
-        //
-        // PROBLEM: We don't have enough endpoints on the peripheral :/
-        // (USBHS should have one more)
-        // let interrupt = allocator.interrupt(8 as _, 32);
-        let pipe = Pipe::new(write, request_pipe, card_issuers_data);
-        let interface_number = allocator.interface();
-        let string_index = allocator.string();
-        Self {
-            interface_number,
-            string_index,
-            read,
-            /* interrupt, */ pipe,
+        Self::try_new(allocator, request_pipe, card_issuers_data)
+            .expect("card issuer's data must be at most 13 bytes")
+    }
+
+    /// Class constructor, with the default dwMaxCCIDMessageLength.
+    ///
+    /// Shorthand for [`CcidBuilder`], returns [`ConfigError::IssuerDataTooLong`] if the card
+    /// issuer's data exceeds 13 bytes.
+    pub fn try_new(
+        allocator: &'bus UsbBusAllocator<Bus>,
+        request_pipe: Requester<'pipe, N>,
+        card_issuers_data: Option<&[u8]>,
+    ) -> core::result::Result<Self, ConfigError> {
+        Self::builder(card_issuers_data).build(allocator, request_pipe)
+    }
+}
+
+impl<'bus, 'pipe, Bus, const N: usize, const MSG: usize> Ccid<'bus, 'pipe, Bus, N, MSG>
+where
+    Bus: 'static + UsbBus,
+{
+    /// Class constructor with a dwMaxCCIDMessageLength of `MSG` bytes, which must be at
+    /// least the packet size.
+    ///
+    /// Shorthand for [`CcidBuilder`], panics if the card issuer's data exceeds 13 bytes.
+    pub fn new_with_message_length(
+        allocator: &'bus UsbBusAllocator<Bus>,
+        request_pipe: Requester<'pipe, N>,
+        card_issuers_data: Option<&[u8]>,
+    ) -> Self {
+        Self::builder(card_issuers_data)
+            .build(allocator, request_pipe)
+            .expect("card issuer's data must be at most 13 bytes")
+    }
+
+    /// Class constructor like [`new_with_message_length`](Self::new_with_message_length),
+    /// additionally allocating an interrupt IN endpoint.
+    ///
+    /// The endpoint carries RDR_to_PC_NotifySlotChange messages, see
+    /// [`notify_slot_change`](Self::notify_slot_change), so that the host does not need to poll
+    /// with GetSlotStatus.  Not all peripherals have an endpoint to spare for this.
+    pub fn new_with_interrupt(
+        allocator: &'bus UsbBusAllocator<Bus>,
+        request_pipe: Requester<'pipe, N>,
+        card_issuers_data: Option<&[u8]>,
+    ) -> Self {
+        Self::builder(card_issuers_data)
+            .interrupt(true)
+            .build(allocator, request_pipe)
+            .expect("card issuer's data must be at most 13 bytes")
+    }
+
+    fn builder(card_issuers_data: Option<&[u8]>) -> CcidBuilder<'_> {
+        let builder = CcidBuilder::new();
+        match card_issuers_data {
+            Some(data) => builder.card_issuers_data(data),
+            None => builder,
         }
     }
 
+    /// Report whether an ICC is present in `slot`.
+    ///
+    /// If the class was created with an interrupt endpoint (see [`CcidBuilder::interrupt`]), the host
+    /// is sent an RDR_to_PC_NotifySlotChange when the presence differs from the last one
+    /// reported.  Rapid toggles are coalesced.  All slots start out with an ICC present.
+    pub fn notify_slot_change(
+        &mut self,
+        slot: u8,
+        present: bool,
+    ) -> core::result::Result<(), ConfigError> {
+        self.pipe.notify_slot_change(slot, present)
+    }
+
+    /// The protocol parameters of `slot`, as reported by GetParameters.
+    ///
+    /// These are the defaults (see [`CcidBuilder::t1_parameters`]) until the host sets others
+    /// with SetParameters.  The IFSD is not negotiated at the CCID layer, see
+    /// [`Parameters::ifsd`].
+    ///
+    /// SetParameters cannot switch the protocol, as the ATR already announced the protocol of
+    /// the class and a switch would need a new PowerOn: a SetParameters for the other
+    /// protocol is rejected with bError 7 (the offset of bProtocolNum), powered or not, and
+    /// the parameters are left unchanged.
+    pub fn parameters(&self, slot: u8) -> core::result::Result<Parameters, ConfigError> {
+        self.pipe.parameters(slot)
+    }
+
+    /// Notify the host of an insertion, a removal or a hardware error in `slot`.
+    ///
+    /// This requires an interrupt endpoint (see [`CcidBuilder::interrupt`]).  Insertion and
+    /// removal are sent as RDR_to_PC_NotifySlotChange like with
+    /// [`notify_slot_change`](Self::notify_slot_change).  A hardware error is sent as
+    /// RDR_to_PC_HardwareError with the sequence number of the last command of the slot, and
+    /// deactivates the ICC until the host powers it on again.
+    pub fn on_card_event(
+        &mut self,
+        slot: u8,
+        event: CardEvent,
+    ) -> core::result::Result<(), ConfigError> {
+        self.pipe.on_card_event(slot, event)
+    }
+
+    /// Set the bmICCStatus reported for `slot` in the bStatus of every response.
+    ///
+    /// Products modelling a removable card can report [`IccStatus::NotPresent`] after removal;
+    /// PowerOn is then rejected with `IccMute`.  PowerOn and PowerOff switch a present ICC
+    /// between [`IccStatus::Active`] and [`IccStatus::Inactive`].
+    pub fn set_icc_status(
+        &mut self,
+        slot: u8,
+        status: IccStatus,
+    ) -> core::result::Result<(), ConfigError> {
+        self.pipe.set_icc_status(slot, status)
+    }
+
+    /// Set the number of slots announced in bMaxSlotIndex, between 1 and [`MAX_SLOTS`].
+    ///
+    /// All slots share the interchange, so only one of them can have a command processed by
+    /// the application at a time; the others answer `XfrBlock` with `CmdSlotBusy` meanwhile.
+    /// Like [`set_features`](Self::set_features), the new value is only seen by the host after
+    /// the next enumeration.
+    pub fn set_num_slots(&mut self, num_slots: u8) -> core::result::Result<(), ConfigError> {
+        self.pipe.set_num_slots(num_slots)
+    }
+
+    /// The number of slots announced in bMaxSlotIndex (default: 1).
+    pub fn num_slots(&self) -> u8 {
+        self.pipe.num_slots()
+    }
+
+    /// Replace the Answer-to-Reset of one slot, e.g. with one built by
+    /// [`AtrBuilder`](crate::AtrBuilder) to match the card being emulated.
+    ///
+    /// The ATR is sent as is by the next PowerOn, so it should announce the protocol of the
+    /// class.  It cannot be replaced while the slot has a transfer in progress.
+    pub fn set_atr(&mut self, slot: u8, atr: &[u8]) -> core::result::Result<(), ConfigError> {
+        self.pipe.set_atr(slot, atr)
+    }
+
+    /// Personalize the Answer-to-Reset of one slot, see [`CcidBuilder::card_issuers_data`].
+    ///
+    /// Initially, all slots use the card issuer's data passed to the constructor.  Like the
+    /// ATR, it cannot be changed while the slot has a transfer in progress.
+    pub fn set_card_issuers_data(
+        &mut self,
+        slot: u8,
+        card_issuers_data: Option<&[u8]>,
+    ) -> core::result::Result<(), ConfigError> {
+        self.pipe.set_card_issuers_data(slot, card_issuers_data)
+    }
+
     /// Read response from application (if any) and start writing it to
     /// the USB bus.  Should be called before managing Bus.
     pub fn check_for_app_response(&mut self) {
         self.poll();
     }
 
+    /// Advance the class like the `UsbClass::poll` of the device, and report whether the
+    /// application responded.
+    ///
+    /// Firmware can use this to schedule wait extensions only while the application is still
+    /// processing a command, and to know when a response started being sent.
+    pub fn poll_with_result(&mut self) -> PollResult {
+        self.pipe.poll()
+    }
+
+    /// Install (or remove) a callback observing every raw USB packet.
+    ///
+    /// See [`PacketTap`] for the performance implications.
+    pub fn set_packet_tap(&mut self, tap: Option<&'static mut PacketTap>) {
+        self.pipe.set_packet_tap(tap);
+    }
+
+    /// Install (or remove) a handler for vendor specific PC_to_RDR_Escape commands.
+    ///
+    /// The handler gets the command payload and fills the response payload, which is sent
+    /// back in an RDR_to_PC_Escape message spanning as many packets as needed.  A response
+    /// longer than the dwMaxCCIDMessageLength allows is rejected with `CmdAborted`.  Without a
+    /// handler, Escape commands are rejected with `CommandNotSupported`.
+    pub fn set_escape_handler(&mut self, handler: Option<EscapeHandler>) {
+        self.pipe.set_escape_handler(handler);
+    }
+
+    /// Select how the sequence numbers of incoming commands are validated.
+    ///
+    /// Defaults to [`SeqPolicy::Tolerant`].
+    pub fn set_seq_policy(&mut self, policy: SeqPolicy) {
+        self.pipe.set_seq_policy(policy);
+    }
+
+    /// Serialize the effective CCID configuration into `buf`, e.g. for support diagnostics.
+    ///
+    /// Layout: format version (1), number of slots, protocol (bProtocolNum), packet size
+    /// (u16 LE), dwMaxCCIDMessageLength (u32 LE), dwFeatures (u32 LE), sequence number policy,
+    /// the protocol parameters (7 bytes, zero padded for T=0), the ATR length and the ATR.
+    /// The parameters and the ATR are those of slot 0.
+    ///
+    /// Returns the number of bytes written, or 0 if `buf` is too small.
+    pub fn config_snapshot(&self, buf: &mut [u8]) -> usize {
+        self.pipe.config_snapshot(buf, self.features)
+    }
+
+    /// Throughput counters, e.g. to log per-transaction metrics.
+    pub fn stats(&self) -> PipeStats {
+        self.pipe.stats()
+    }
+
+    /// Reset the counters returned by [`stats`](Self::stats).
+    pub fn reset_stats(&mut self) {
+        self.pipe.reset_stats();
+    }
+
+    /// The number of transfers dropped because of malformed or unexpected input from the host
+    /// (or a failing endpoint), wrapping.  A steadily growing count hints at a misbehaving host.
+    pub fn reset_count(&self) -> u32 {
+        self.pipe.reset_count()
+    }
+
+    /// The last error of the bulk IN endpoint, if it has not been taken yet.
+    ///
+    /// Such errors drop the transfer in progress, see [`reset_count`](Self::reset_count).  As
+    /// they occur while the bus is polled, firmware checks this afterwards to decide whether
+    /// to reset the peripheral or re-enumerate.
+    pub fn take_write_error(&mut self) -> Option<UsbError> {
+        self.pipe.take_write_error()
+    }
+
+    /// Why the most recent transfer was dropped, if it has not been taken yet.
+    pub fn take_last_reset_reason(&mut self) -> Option<ResetReason> {
+        self.pipe.take_last_reset_reason()
+    }
+
+    /// The most recent error reported to the host, if any.
+    pub fn last_error(&self) -> Option<CcidError> {
+        self.pipe.last_error()
+    }
+
+    /// Whether the most recent response sent to the host was a slot status error.
+    pub fn last_response_was_error(&self) -> bool {
+        self.pipe.last_response_was_error()
+    }
+
+    /// Abort the current transfers of all slots without sending anything to the host.
+    ///
+    /// Intended for teardown paths where the host is gone.  Aborts requested by the host are
+    /// acknowledged by the driver itself.
+    pub fn abort_silent(&mut self) {
+        self.pipe.abort_silent();
+    }
+
+    /// Signal whether the application is ready to process APDUs (default: ready).
+    ///
+    /// During boot, firmware can set this to `false` until the application is initialized.
+    /// Meanwhile, the host gets a `CmdSlotBusy` error for every command except PowerOn and
+    /// retries later.
+    pub fn set_ready(&mut self, ready: bool) {
+        self.pipe.set_ready(ready);
+    }
+
+    /// Override the dwFeatures reported in the functional descriptor.
+    ///
+    /// This is intended for diagnosing host quirks.  The new value is only seen by the host
+    /// after the next enumeration, so the device has to be re-enumerated.  As only APDU level
+    /// exchange is implemented, the features must select short or extended APDU level exchange,
+    /// the latter only if `N` exceeds [`SHORT_APDU_LEN`].
+    pub fn set_features(&mut self, features: u32) -> core::result::Result<(), ConfigError> {
+        check_features(features, N)?;
+        self.features = features;
+        Ok(())
+    }
+
+    /// The dwFeatures reported in the functional descriptor.
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+
+    /// The functional descriptor as reported to the host, without bLength and bDescriptorType.
+    ///
+    /// Use [`CcidDescriptor::parse`][crate::CcidDescriptor::parse] to inspect its fields.
+    pub fn functional_descriptor(&self) -> [u8; FUNCTIONAL_INTERFACE_DESCRIPTOR_LEN] {
+        let mut descriptor = FUNCTIONAL_INTERFACE_DESCRIPTOR;
+        descriptor[BCD_CCID_OFFSET..][..2].copy_from_slice(&self.bcd_ccid.to_le_bytes());
+        descriptor[FEATURES_OFFSET..][..4].copy_from_slice(&self.features.to_le_bytes());
+        descriptor[MAX_SLOT_INDEX_OFFSET] = self.pipe.num_slots() - 1;
+        descriptor[PIN_SUPPORT_OFFSET] = self.pipe.pin_support();
+        descriptor[PROTOCOLS_OFFSET] = 1 << self.pipe.protocol().number();
+        descriptor[MAX_MSG_LENGTH_OFFSET..][..4]
+            .copy_from_slice(&(self.pipe.max_message_length() as u32).to_le_bytes());
+        descriptor
+    }
+
+    /// Select how GetParameters is answered before the host powered on the ICC.
+    ///
+    /// By default, the default T=1 parameters are returned at any time, as some host stacks
+    /// query them during enumeration.  If `require` is set, GetParameters fails with `IccMute`
+    /// until the next PowerOn.
+    pub fn set_parameters_require_power(&mut self, require: bool) {
+        self.pipe.set_parameters_require_power(require);
+    }
+
+    /// The state of the transfer in progress, for diagnostics.
+    ///
+    /// As the slots share the interchange, at most one of them has a transfer in progress.
+    pub fn state(&self) -> State {
+        self.pipe.state()
+    }
+
+    /// Whether a command is being received, processed or answered, including a T=0 response
+    /// waiting for GET RESPONSE, which still holds the interchange.
+    ///
+    /// A transfer that stays busy for long hints at a wedged host or application.
+    pub fn is_busy(&self) -> bool {
+        self.state() != State::Idle
+    }
+
+    /// Suggested delay in milliseconds before calling [`check_for_app_response`](Self::check_for_app_response) again.
+    ///
+    /// Returns `Some` while the application is processing a command, so that firmware polling
+    /// in a tight loop can back off using its own timer, and `None` otherwise.
+    pub fn suggested_poll_delay(&self) -> Option<u32> {
+        self.pipe.suggested_poll_delay()
+    }
+
+    /// Send the ATR to the host, e.g. after a reset triggered by the firmware.
+    ///
+    /// The ATR is sent for the slot addressed by the last command, and only if no transfer is
+    /// in progress on it; returns whether it was sent.
+    pub fn send_atr_now(&mut self) -> bool {
+        self.pipe.send_atr_now()
+    }
+
+    /// Advance the class and report whether a wait extension has to be scheduled.
+    ///
+    /// Call this from the USB interrupt (or task) right after `UsbDevice::poll`, in place of
+    /// [`check_for_app_response`](Self::check_for_app_response) and
+    /// [`did_start_processing`](Self::did_start_processing).  It picks up the response of the
+    /// application, writes pending packets and notifications, and returns
+    /// `Status::ReceivedData(delay)` when the application started processing a command: the
+    /// caller then arms a timer calling [`send_wait_extension`](Self::send_wait_extension)
+    /// after `delay`, re-arming it as long as that returns `ReceivedData`.
+    pub fn poll_status(&mut self) -> Status {
+        self.pipe.poll();
+        self.did_start_processing()
+    }
+
     pub fn did_start_processing(&mut self) -> Status {
         if self.pipe.did_start_processing() {
             // We should send a wait extension later
@@ -71,6 +626,88 @@ where
         }
     }
 
+    /// Set the BWT multiplier requested by each time extension (default: 1).
+    ///
+    /// The host grants the application this many block waiting times (derived from the BWI in
+    /// the T=1 parameters) before it expects the response or the next time extension.  Slow
+    /// operations such as RSA key generation may need a larger value.  The value is clamped
+    /// to 1..=16.  A larger bBWI granted by the host in the command is requested instead.
+    ///
+    /// Each call of [`send_wait_extension`](Self::send_wait_extension) is taken to span one
+    /// block waiting time: the host already granted the bBWI of the command and the
+    /// multiplier of the last time extension, so no extension is sent before they elapsed.
+    pub fn set_wait_extension_multiplier(&mut self, multiplier: u8) {
+        self.pipe.set_wait_extension_multiplier(multiplier);
+    }
+
+    /// Abandon the command the application is processing, e.g. because the user removed their
+    /// finger from a fingerprint sensor, and answer it with `CmdAborted`.
+    ///
+    /// This is safe to call at any time: it returns whether a command was cancelled, and does
+    /// nothing while no command is being processed.  The cancelled request is withdrawn from
+    /// the interchange, so the application must not respond to it anymore.
+    pub fn cancel(&mut self) -> bool {
+        self.pipe.cancel()
+    }
+
+    /// Give up on a command if the application does not respond in time (default: never).
+    ///
+    /// `ticks` counts the calls of [`send_wait_extension`](Self::send_wait_extension) while
+    /// the application is processing, about one per second.  Once exceeded, the command is
+    /// cancelled over the interchange and the host gets an `IccMute` error, so that it can
+    /// recover instead of waiting on a wedged slot forever.  Polling does not advance the
+    /// count, so firmware that never sends time extensions never times out either.
+    pub fn set_processing_timeout(&mut self, ticks: Option<u32>) {
+        self.pipe.set_processing_timeout(ticks);
+    }
+
+    /// Whether a response is still being written to the host.
+    ///
+    /// Firmware should not enter a sleep mode stalling the bulk IN endpoint while this is set.
+    /// Together with [`did_start_processing`](Self::did_start_processing), this tells whether
+    /// the class is idle.  A T=0 response waiting for GET RESPONSE does not count, as the host
+    /// has to send a command first.
+    pub fn response_in_progress(&self) -> bool {
+        self.pipe.response_in_progress()
+    }
+
+    /// Whether the command being processed came from a `PC_to_RDR_Secure` message.
+    ///
+    /// Instead of a command APDU, the interchange then carries the abData of the message:
+    /// bPINOperation (0 for verification, 1 for modification) followed by the PIN data
+    /// structure with the APDU template, so that the application can verify the PIN on-device.
+    /// The response APDU is returned to the host in a DataBlock as usual.  PIN operations not
+    /// advertised with [`CcidBuilder::pin_support`] and chained Secure messages are rejected
+    /// with the offset of the offending field.
+    pub fn is_secure_command(&self) -> bool {
+        self.pipe.is_secure_command()
+    }
+
+    /// Let the application stream responses with [`Ccid::stream_response`] (default: no).
+    ///
+    /// By default, the application answers with the complete response APDU over the
+    /// interchange, so `N` must hold the largest response (e.g. a 2 KB certificate) and nothing
+    /// is sent before it is complete.  In streaming mode, the interchange only carries the
+    /// command APDU: the application pushes the response in chunks, each block being sent as
+    /// soon as it is complete, chained with `Begins`/`Continues`/`Ends`.  In exchange, the
+    /// application has to call into the `Ccid` instead of responding over the interchange,
+    /// the host waits for each block while it is being produced (without time extensions once
+    /// the first block went out), and responses cannot signal a CCID error or be segmented
+    /// for T=0.
+    pub fn set_streaming(&mut self, streaming: bool) {
+        self.pipe.set_streaming(streaming);
+    }
+
+    /// Streaming mode: push the next chunk of the response to the command being processed,
+    /// with `last` set on the final chunk.  The application then hands the interchange back
+    /// with an empty response, its content is ignored.
+    ///
+    /// Returns the number of bytes accepted.  The pipe buffers about two packets ahead of the
+    /// host, so the application retries the rest after the host fetched the next block.
+    pub fn stream_response(&mut self, data: &[u8], last: bool) -> usize {
+        self.pipe.stream_response(data, last)
+    }
+
     pub fn send_wait_extension(&mut self) -> Status {
         if self.pipe.send_wait_extension() {
             // We should send another wait extension later
@@ -81,7 +718,8 @@ where
     }
 }
 
-impl<'bus, 'pipe, Bus, const N: usize> UsbClass<Bus> for Ccid<'bus, 'pipe, Bus, N>
+impl<'bus, 'pipe, Bus, const N: usize, const MSG: usize> UsbClass<Bus>
+    for Ccid<'bus, 'pipe, Bus, N, MSG>
 where
     Bus: UsbBus,
 {
@@ -94,10 +732,12 @@ where
             TransferMode::Bulk as u8,
             Some(self.string_index),
         )?;
-        writer.write(FUNCTIONAL_INTERFACE, &FUNCTIONAL_INTERFACE_DESCRIPTOR)?;
+        writer.write(FUNCTIONAL_INTERFACE, &self.functional_descriptor())?;
         writer.endpoint(&self.pipe.write).ok();
         writer.endpoint(&self.read).ok();
-        // writer.endpoint(&self.interrupt).ok();
+        if let Some(interrupt) = &self.pipe.interrupt {
+            writer.endpoint(interrupt).ok();
+        }
         Ok(())
     }
 
@@ -108,16 +748,15 @@ where
     #[inline(never)]
     fn poll(&mut self) {
         // info_now!("poll of ccid");
-        self.pipe.poll_app();
-        self.pipe.maybe_send_packet();
+        self.pipe.poll();
     }
 
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
-        if addr != self.pipe.write.address() {
-            return;
+        if addr == self.pipe.write.address() {
+            self.pipe.maybe_send_packet().ok();
+        } else if self.pipe.interrupt.as_ref().map(|ep| ep.address()) == Some(addr) {
+            self.pipe.maybe_send_notification();
         }
-
-        self.pipe.maybe_send_packet();
     }
 
     fn endpoint_out(&mut self, addr: EndpointAddress) {
@@ -177,7 +816,7 @@ where
                         }
                         _ => {
                             error!("unexpected direction for {:?}", &request);
-                            self.pipe.reset_state();
+                            self.pipe.reset_after(ResetReason::UnexpectedRequest);
                         }
                     }
                 }
@@ -200,6 +839,19 @@ where
             value,
             ..
         } = *transfer.request();
+        if (request_type, recipient, request, value)
+            == (
+                RequestType::Standard,
+                Recipient::Endpoint,
+                Request::CLEAR_FEATURE,
+                Request::FEATURE_ENDPOINT_HALT,
+            )
+            && index as u8 == u8::from(self.read.address())
+        {
+            // The device clears the halt itself, we only need to drop any partial message.
+            self.pipe.bulk_out_reset();
+            return;
+        }
         if index as u8 != u8::from(self.interface_number) {
             return;
         }
@@ -220,7 +872,7 @@ where
                         }
                         _ => {
                             error!("unexpected direction for {:?}", &request);
-                            self.pipe.reset_state();
+                            self.pipe.reset_after(ResetReason::UnexpectedRequest);
                         }
                     }
                 }
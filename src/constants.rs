@@ -1,11 +1,17 @@
 // can be 8, 16, 32, 64 or 512
+/// The wMaxPacketSize of the bulk endpoints, 512 bytes with the `highspeed-usb` feature.
 #[cfg(feature = "highspeed-usb")]
 pub const PACKET_SIZE: usize = 512;
+/// The wMaxPacketSize of the bulk endpoints, 512 bytes with the `highspeed-usb` feature.
 #[cfg(not(feature = "highspeed-usb"))]
 pub const PACKET_SIZE: usize = 64;
 
 pub const CCID_HEADER_LEN: usize = 10;
 
+// RDR_to_PC_NotifySlotChange is 2 bytes for up to 4 slots
+pub const INTERRUPT_PACKET_SIZE: u16 = 8;
+pub const INTERRUPT_INTERVAL_MS: u8 = 32;
+
 pub const CLASS_CCID: u8 = 0x0B;
 pub const SUBCLASS_NONE: u8 = 0x0;
 
@@ -44,23 +50,112 @@ pub const MAX_IFSD: [u8; 4] = [0xfe, 0x00, 0x00, 0x00];
 // dwMaxCCIDMsgLen 3072
 pub const MAX_MSG_LENGTH: usize = 3072;
 pub const MAX_MSG_LENGTH_LE: [u8; 4] = (MAX_MSG_LENGTH as u32).to_le_bytes();
+// offset of dwMaxCCIDMessageLength in FUNCTIONAL_INTERFACE_DESCRIPTOR
+pub const MAX_MSG_LENGTH_OFFSET: usize = 42;
+
+// bcdCCID, see FUNCTIONAL_INTERFACE_DESCRIPTOR
+pub const BCD_CCID: u16 = 0x0110;
+pub const BCD_CCID_OFFSET: usize = 0;
+
+// dwFeatures, see FUNCTIONAL_INTERFACE_DESCRIPTOR
+pub const FEATURES: u32 = 0x0004_0840;
+pub const FEATURES_LE: [u8; 4] = FEATURES.to_le_bytes();
+// exchange level bits of dwFeatures: TPDU, short APDU, short+extended APDU
+pub const FEATURES_EXCHANGE_LEVEL_MASK: u32 = 0x0007_0000;
+pub const FEATURES_SHORT_APDU: u32 = 0x0002_0000;
+pub const FEATURES_EXTENDED_APDU: u32 = 0x0004_0000;
+// the default dwFeatures of an interchange too small for extended APDUs
+pub const SHORT_APDU_FEATURES: u32 =
+    (FEATURES & !FEATURES_EXCHANGE_LEVEL_MASK) | FEATURES_SHORT_APDU;
+// offset of dwFeatures in FUNCTIONAL_INTERFACE_DESCRIPTOR
+pub const FEATURES_OFFSET: usize = 38;
+
+// CLA INS P1 P2 Lc (255 bytes of data) Le
+pub const SHORT_APDU_LEN: usize = 261;
+// the largest APDU a single CCID message can carry
+pub const EXTENDED_APDU_LEN: usize = MAX_MSG_LENGTH - CCID_HEADER_LEN;
+/// The message buffer of [`ShortApduCcid`](crate::ShortApduCcid): the smallest one holding a
+/// short APDU, and at least one packet.
+pub const SHORT_MSG_LENGTH: usize = if SHORT_APDU_LEN + CCID_HEADER_LEN > PACKET_SIZE {
+    SHORT_APDU_LEN + CCID_HEADER_LEN
+} else {
+    PACKET_SIZE
+};
 
 pub const MAX_BUSY_SLOTS: u8 = 1;
+
+// upper bound for the BWT multiplier of time extensions, the host waits up to this many BWTs
+pub const MAX_WAIT_EXTENSION_MULTIPLIER: u8 = 16;
+
+// the ATR is at most 32 bytes (ISO 7816-3)
+pub const MAX_ATR_LEN: usize = 32;
+
+// number of slots the pipe keeps state for, the descriptor announces the configured number
+pub const MAX_SLOTS: usize = 2;
+// offset of bMaxSlotIndex in FUNCTIONAL_INTERFACE_DESCRIPTOR
+pub const MAX_SLOT_INDEX_OFFSET: usize = 2;
+
+// bProtocolNum for T=0 and T=1
+pub const PROTOCOL_T0: u8 = 0;
+pub const PROTOCOL_T1: u8 = 1;
+// offset of dwProtocols in FUNCTIONAL_INTERFACE_DESCRIPTOR
+pub const PROTOCOLS_OFFSET: usize = 4;
+
+// abProtocolDataStructure for T=0, as reported by RDR_to_PC_Parameters
+pub const T0_PARAMETERS: [u8; 5] = [
+    // bmFindexDindex: same as for T=1
+    (0b0001 << 4) | (0b0001),
+    // bmTCCKST0: direct convention
+    0x00,
+    // bGuardTimeT0
+    0x00,
+    // bWaitingIntegerT0: default WI
+    0x0a,
+    // bClockStop
+    0x00,
+];
+// T=0 transfers at most 256 bytes of response data, the rest is fetched with GET RESPONSE
+pub const T0_RESPONSE_CHUNK: usize = 256;
+
+// abProtocolDataStructure for T=1, as reported by RDR_to_PC_Parameters
+pub const T1_PARAMETERS: [u8; 7] = [
+    // bmFindexDindex: just picking the fastest values.
+    //   Fi = 1Mz    Di=1
+    (0b0001 << 4) | (0b0001),
+    // bmTCCKST1: just taking default value from spec.
+    0x10,
+    // bGuardTimeT1
+    0x00,
+    // bmWaitingIntegersT1: not sure, taking default.
+    0x15,
+    // bClockStop
+    0x00,
+    // bIFSC: set max
+    0xfe,
+    // bNadValue
+    0x00,
+];
 // bPinSupport (0x0 = none, 0x01 = verification, 0x02 = modification)
 pub const PIN_SUPPORT: u8 = 0;
+pub const PIN_SUPPORT_VERIFICATION: u8 = 0x01;
+pub const PIN_SUPPORT_MODIFICATION: u8 = 0x02;
+
+pub const FUNCTIONAL_INTERFACE_DESCRIPTOR_LEN: usize = 52;
+// offsets of bPinSupport and bMaxCCIDBusySlots in FUNCTIONAL_INTERFACE_DESCRIPTOR
+pub const PIN_SUPPORT_OFFSET: usize = 50;
+pub const MAX_BUSY_SLOTS_OFFSET: usize = 51;
 
 // cf. Sec. 5.1 in: https://www.usb.org/sites/default/files/DWG_Smart-Card_CCID_Rev110.pdf
-pub const FUNCTIONAL_INTERFACE_DESCRIPTOR: [u8; 52] = [
+pub const FUNCTIONAL_INTERFACE_DESCRIPTOR: [u8; FUNCTIONAL_INTERFACE_DESCRIPTOR_LEN] = [
     // bcdCCID rev1.10
     0x10,
     0x01,
-    // bMaxSlotIndex
-    // NUM_SLOTS - 1,
+    // bMaxSlotIndex, overwritten with the configured number of slots - 1
     // "An USB-ICC is regarded as a single slot CCID."
     0x00,
     // bVoltageSupport (5.0V)
     0x01,
-    // dwProtocols: APDU level, T=1 only (0 = T=0, 3 = T0+T1)
+    // dwProtocols: APDU level, T=1 only (1 = T=0, 3 = T0+T1), overwritten with the configured protocol
     0x02,
     0x00,
     0x00,
@@ -115,10 +210,10 @@ pub const FUNCTIONAL_INTERFACE_DESCRIPTOR: [u8; 52] = [
     // 0xFE, 0x00, 0x04, 0x00,
     // ICCD: lower word (=0840): only requests valid for USB-ICC
     // upper word: 0000 = char level, 0002 = short APDU, 0004 = short+exteded APDU
-    0x40,
-    0x08,
-    0x04,
-    0x00,
+    FEATURES_LE[0],
+    FEATURES_LE[1],
+    FEATURES_LE[2],
+    FEATURES_LE[3],
     // dwMaxCCIDMsgLen (3072)
     // gnuk: 271
     MAX_MSG_LENGTH_LE[0],
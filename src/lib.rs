@@ -8,16 +8,63 @@
 //!
 //! [CCID SpecificationUSB Integrated Circuit(s) Card Devices](https://www.usb.org/sites/default/files/DWG_Smart-Card_USB-ICC_ICCD_rev10.pdf)
 
+#[cfg(feature = "log")]
 #[macro_use]
 extern crate delog;
+#[cfg(feature = "log")]
 generate_macros!();
 
+// Same expansion as the disabled `delog` macros, without pulling in `delog`
+#[cfg(not(feature = "log"))]
+#[macro_use]
+#[allow(unused_macros)]
+mod log {
+    macro_rules! debug {
+        ($($arg:tt)*) => {
+            core::result::Result::<(), ()>::Ok(()).ok()
+        };
+    }
+    macro_rules! error {
+        ($($arg:tt)*) => {
+            core::result::Result::<(), ()>::Ok(()).ok()
+        };
+    }
+    macro_rules! info {
+        ($($arg:tt)*) => {
+            core::result::Result::<(), ()>::Ok(()).ok()
+        };
+    }
+    macro_rules! info_now {
+        ($($arg:tt)*) => {
+            core::result::Result::<(), ()>::Ok(()).ok()
+        };
+    }
+    macro_rules! warn {
+        ($($arg:tt)*) => {
+            core::result::Result::<(), ()>::Ok(()).ok()
+        };
+    }
+}
+
 mod class;
 mod constants;
+#[cfg(feature = "loopback")]
+mod loopback;
 mod pipe;
 mod types;
 
 // pub mod piv;
 
-pub use class::Ccid;
-pub use types::Status;
+pub use class::{Ccid, CcidBuilder, ExtendedApduCcid, ShortApduCcid};
+pub use constants::{
+    EXTENDED_APDU_LEN, FUNCTIONAL_INTERFACE_DESCRIPTOR_LEN, MAX_ATR_LEN, MAX_SLOTS, PACKET_SIZE,
+    SHORT_APDU_LEN, SHORT_MSG_LENGTH,
+};
+#[cfg(feature = "loopback")]
+pub use loopback::LoopbackResponder;
+pub use pipe::{EscapeHandler, PacketTap, PipeStats, PollResult, State};
+pub use types::packet;
+pub use types::{
+    AtrBuilder, AtrError, CardEvent, CcidDescriptor, CcidError, ConfigError, IccStatus, Parameters,
+    Protocol, ResetReason, SeqPolicy, Status, T1Parameters,
+};
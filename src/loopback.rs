@@ -0,0 +1,35 @@
+//! Loopback application for bring-up.
+//!
+//! Echoes every command APDU back as the response, followed by SW 9000.  Wiring it to the
+//! responder side of the interchange verifies the full USB ↔ CCID path before the real
+//! application exists.  This is a development aid, not meant for production.
+
+pub type Responder<'pipe, const N: usize> =
+    interchange::Responder<'pipe, iso7816::Data<N>, iso7816::Data<N>>;
+
+pub struct LoopbackResponder<'pipe, const N: usize> {
+    responder: Responder<'pipe, N>,
+}
+
+impl<'pipe, const N: usize> LoopbackResponder<'pipe, N> {
+    pub fn new(responder: Responder<'pipe, N>) -> Self {
+        Self { responder }
+    }
+
+    /// Answer the pending command, if any.  Should be called from the application loop.
+    ///
+    /// Returns `true` if a response was sent.  The echoed command is truncated if it does not
+    /// fit into `N` bytes together with the status word.
+    pub fn poll(&mut self) -> bool {
+        if self.responder.is_canceled() {
+            self.responder.acknowledge_cancel().ok();
+            return false;
+        }
+        let Some(mut response) = self.responder.take_request() else {
+            return false;
+        };
+        response.truncate(N.saturating_sub(2));
+        response.extend_from_slice(&[0x90, 0x00]).ok();
+        self.responder.respond(response).is_ok()
+    }
+}
@@ -1,153 +1,910 @@
 use core::convert::TryFrom;
-use heapless::Vec;
+use heapless::{Deque, Vec};
 
 use crate::{
     constants::*,
-    types::packet::{
-        Chain, ChainedPacket as _, Command as PacketCommand, DataBlock, Error as PacketError,
-        ExtPacket, PacketWithData as _, RawPacket, RawPacketExt as _, XfrBlock,
+    types::{
+        packet::{
+            Chain, ChainedPacket as _, Command as PacketCommand, DataBlock, Error as PacketError,
+            Escape, ExtPacket, PacketWithData as _, RawPacket, RawPacketExt as _, Secure,
+            SetDataRateAndClockFrequency, SetParameters, XfrBlock,
+        },
+        AtrBuilder, CardEvent, CcidError, ConfigError, IccStatus, Parameters, Protocol,
+        ResetReason, SeqPolicy, T1Parameters,
     },
 };
 
-use usb_device::class_prelude::*;
+use usb_device::{class_prelude::*, UsbDirection};
 
 #[allow(clippy::assertions_on_constants)]
 const _: () = assert!(MAX_MSG_LENGTH >= PACKET_SIZE);
 
+// Suggested delay between two polls of the application while it is processing.
+const PROCESSING_POLL_DELAY_MS: u32 = 10;
+
+// Enough for a status reply and a response chunk to coexist.
+const OUTBOX_LEN: usize = 2;
+
+// The response data carried by a single-packet DataBlock.
+const STREAM_BLOCK_LEN: usize = PACKET_SIZE - CCID_HEADER_LEN;
+// Streaming mode: the response bytes buffered ahead of the blocks being sent.
+const STREAM_BUFFER_LEN: usize = 2 * STREAM_BLOCK_LEN;
+
+/// Transfer state of a slot, see [`Ccid::state`](crate::Ccid::state).
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum State {
+    /// No transfer in progress.
     Idle,
+    /// Receiving a chained command APDU.
     Receiving,
+    /// The application is processing the command APDU.
     Processing,
+    /// The response APDU is available and about to be sent.
     ReadyToSend,
+    /// Sending a chained response APDU.
     Sending,
+    /// T=0: the response was sent up to SW 61XX, the rest waits for GET RESPONSE.
+    ResponsePending,
 }
 
+/// Outcome of polling the application, see `Ccid::poll_with_result`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[allow(dead_code, clippy::enum_variant_names)]
-enum Error {
-    CmdAborted = 0xff,
-    IccMute = 0xfe,
-    XfrParityError = 0xfd,
-    //..
-    CmdSlotBusy = 0xE0,
-    CommandNotSupported = 0x00,
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PollResult {
+    /// The application responded, the response is being sent.
+    ResponseReady,
+    /// The application is still working on the command.
+    StillProcessing,
+    /// No command is being processed, polling was a no-op.
+    NotProcessing,
+}
+
+/// Counters for throughput and observability, see [`Ccid::stats`](crate::Ccid::stats).
+///
+/// All counters wrap around.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PipeStats {
+    /// Bytes received on the bulk OUT endpoint.
+    pub bytes_received: u32,
+    /// Bytes written to the bulk IN endpoint.
+    pub bytes_sent: u32,
+    /// Commands forwarded to the application.
+    pub apdus: u32,
+    /// Time extensions requested while the application was processing.
+    pub wait_extensions: u32,
+    /// Aborts acknowledged to the host.
+    pub aborts: u32,
+    /// Status packets dropped because the endpoint did not drain the outbox in time.
+    pub dropped_packets: u32,
 }
 
+/// Handler for vendor specific PC_to_RDR_Escape commands.
+///
+/// It is called with the abData of the command, writes the abData of the RDR_to_PC_Escape
+/// response into the buffer and returns its length.  The buffer holds dwMaxCCIDMessageLength
+/// minus the 10 byte header.
+pub type EscapeHandler = fn(&[u8], &mut [u8]) -> usize;
+
+/// Callback observing the raw bytes of every USB packet handled by the pipe.
+///
+/// It is called synchronously on the USB path, so it should be kept short
+/// (e.g. copy into a ring buffer) to avoid delaying the CCID responses.
+pub type PacketTap = dyn FnMut(UsbDirection, &[u8]);
+
 pub(crate) type Requester<'pipe, const N: usize> =
     interchange::Requester<'pipe, iso7816::Data<N>, iso7816::Data<N>>;
 
-pub struct Pipe<'bus, 'pipe, Bus, const N: usize>
+// `UsbError` is neither `Copy` nor `Clone`, but a write error is both recorded and returned.
+fn copy_usb_error(error: &UsbError) -> UsbError {
+    match error {
+        UsbError::WouldBlock => UsbError::WouldBlock,
+        UsbError::ParseError => UsbError::ParseError,
+        UsbError::BufferOverflow => UsbError::BufferOverflow,
+        UsbError::EndpointOverflow => UsbError::EndpointOverflow,
+        UsbError::EndpointMemoryOverflow => UsbError::EndpointMemoryOverflow,
+        UsbError::InvalidEndpoint => UsbError::InvalidEndpoint,
+        UsbError::Unsupported => UsbError::Unsupported,
+        UsbError::InvalidState => UsbError::InvalidState,
+    }
+}
+
+// The message type answering a PC_to_RDR command (CCID 6.2), errors included.  Unknown
+// commands are answered with RDR_to_PC_SlotStatus.
+fn response_type(command: u8) -> u8 {
+    match command {
+        // XfrBlock, PowerOn, Secure
+        0x6f | 0x62 | 0x69 => 0x80,
+        // GetParameters, SetParameters, ResetParameters
+        0x6c | 0x61 | 0x6d => 0x82,
+        // Escape
+        0x6b => 0x83,
+        // SetDataRateAndClockFrequency
+        0x73 => 0x84,
+        _ => 0x81,
+    }
+}
+
+/// Check that the card issuer's data fits into the ATR built by the pipe.
+///
+/// It is sent as historical bytes, after a category indicator and a tag byte.  Their number
+/// is encoded in the low nibble of T0, and the whole ATR has to fit into [`MAX_ATR_LEN`] bytes,
+/// which leaves room for at most 13 bytes of data.
+pub(crate) fn check_card_issuers_data(card_issuers_data: Option<&[u8]>) -> Result<(), ConfigError> {
+    atr_builder(card_issuers_data, Protocol::T1, false)
+        .build()
+        .map(drop)
+        .map_err(|_| ConfigError::IssuerDataTooLong)
+}
+
+// The length of a command APDU up to the end of its command data, as declared by Lc, or `None`
+// if the header is incomplete.  Le is not included, as it is optional.
+fn declared_apdu_len(apdu: &[u8]) -> Option<usize> {
+    match *apdu {
+        // with fewer bytes, 00 Le1 Le2 would be an extended Le
+        [_, _, _, _, 0, lc1, lc2, _, ..] => Some(7 + u16::from_be_bytes([lc1, lc2]) as usize),
+        [_, _, _, _, lc, _, ..] if lc != 0 => Some(5 + lc as usize),
+        _ => None,
+    }
+}
+
+fn atr_builder(
+    card_issuers_data: Option<&[u8]>,
+    protocol: Protocol,
+    fast_timing: bool,
+) -> AtrBuilder<'_> {
+    let mut builder = match protocol {
+        Protocol::T0 => AtrBuilder::new().protocol_t0(),
+        Protocol::T1 => AtrBuilder::new().protocol_t1(),
+    };
+    if fast_timing {
+        builder = builder.fast_timing();
+    }
+    match card_issuers_data {
+        Some(data) => builder.card_issuers_data(data),
+        None => builder,
+    }
+}
+
+// Per-slot state.  The bulk endpoints and the interchange are shared by all slots.
+struct Slot {
+    seq: u8,
+    state: State,
+    sent: usize,
+    // Packets waiting to be written to the endpoint, in order.
+    outbox: Deque<RawPacket, OUTBOX_LEN>,
+    atr: Vec<u8, MAX_ATR_LEN>,
+    // abProtocolDataStructure, as set by the host
+    parameters: Vec<u8, 7>,
+    // The sequence number of the last bulk command if it was an abort command.
+    bulk_abort: Option<u8>,
+    // The sequence number of the last abort command received over the control pipe, if any.
+    control_abort: Option<u8>,
+    // Whether the host powered on the ICC (and did not power it off since).
+    powered: bool,
+    // bmICCStatus, tracking PowerOn/PowerOff and the presence set by the firmware.
+    icc_status: IccStatus,
+    // The presence last reported to the host with RDR_to_PC_NotifySlotChange.
+    reported_present: bool,
+    // While receiving: the command does not fit into the interchange, the rest of the chain is
+    // swallowed and the command rejected with SW 6700 once it ends.
+    discarding_chain: bool,
+    // The bHardwareErrorCode of an RDR_to_PC_HardwareError still to be sent.
+    hardware_error: Option<u8>,
+    // While an RDR_to_PC_Escape spans several packets: the bytes of the response queued so
+    // far, the rest is queued packet by packet, see `prime_escape`.
+    escape_sent: Option<usize>,
+}
+
+impl Slot {
+    fn new(atr: Vec<u8, MAX_ATR_LEN>, protocol: Protocol) -> Self {
+        Self {
+            seq: 0,
+            state: State::Idle,
+            sent: 0,
+            outbox: Deque::new(),
+            atr,
+            parameters: Vec::from_slice(protocol.default_parameters())
+                .expect("protocol parameters have at most 7 bytes"),
+            bulk_abort: None,
+            control_abort: None,
+            powered: false,
+            icc_status: IccStatus::Active,
+            reported_present: true,
+            discarding_chain: false,
+            hardware_error: None,
+            escape_sent: None,
+        }
+    }
+
+    fn present(&self) -> bool {
+        self.icc_status != IccStatus::NotPresent
+    }
+
+    fn reset(&mut self) {
+        self.seq = 0;
+        self.state = State::Idle;
+        self.sent = 0;
+        self.outbox.clear();
+        self.escape_sent = None;
+        self.bulk_abort = None;
+        self.control_abort = None;
+    }
+
+    fn abort(&mut self) {
+        self.bulk_abort = None;
+        self.control_abort = None;
+        self.state = State::Idle;
+        self.outbox.clear();
+        self.escape_sent = None;
+    }
+}
+
+pub struct Pipe<'bus, 'pipe, Bus, const N: usize, const MSG: usize>
 where
     Bus: 'static + UsbBus,
 {
     pub(crate) write: EndpointIn<'bus, Bus>,
+    // Optional interrupt IN endpoint for RDR_to_PC_NotifySlotChange.
+    pub(crate) interrupt: Option<EndpointIn<'bus, Bus>>,
     // pub(crate) rpc: TransportEndpoint<'rpc>,
-    seq: u8,
-    state: State,
+    slots: [Slot; MAX_SLOTS],
+    // The number of slots announced to the host, at most MAX_SLOTS.
+    num_slots: u8,
+    // The slot addressed by the command (or response) being handled.
+    current: usize,
+    protocol: Protocol,
+    // Whether the ATRs announce the Fi/Di of `AtrBuilder::fast_timing`.
+    fast_timing: bool,
+    // T=0: the part of the response being sent, followed by SW 61XX or the final status word.
+    t0_segment: Vec<u8, { T0_RESPONSE_CHUNK + 2 }>,
+    // T=0: offset of the next segment in the response data.
+    t0_offset: usize,
+    // T=0: whether more segments remain after `t0_segment`.
+    t0_more: bool,
     interchange: Requester<'pipe, N>,
-    sent: usize,
-    outbox: Option<RawPacket>,
-
-    ext_packet: ExtPacket,
+    // Whether the application pushes responses with `stream_response` instead of the interchange.
+    streaming: bool,
+    // Streaming mode: response bytes pushed by the application and not yet sent.
+    stream: Deque<u8, STREAM_BUFFER_LEN>,
+    // Streaming mode: whether the application pushed the end of the response.
+    stream_complete: bool,
+    // Streaming mode: whether the host waits for a block that has not been pushed yet.
+    stream_requested: bool,
+
+    ext_packet: ExtPacket<MSG>,
+    // The advertised dwMaxCCIDMessageLength, at most MSG.
+    max_message_length: usize,
+    // The BWT multiplier requested by time extensions.
+    wait_extension_multiplier: u8,
+    // The number of time extensions after which an unresponsive application is given up.
+    processing_timeout: Option<u32>,
+    // The number of time extensions requested for the command being processed.
+    processing_ticks: u32,
+    // Whether the command being processed came from a Secure message.
+    secure: bool,
+    // The advertised bPINSupport, selecting the PIN operations forwarded to the application.
+    pin_support: u8,
+    // The bBWI of the command being processed, raising the multiplier of time extensions.
+    command_bwi: u8,
+    // The block waiting times granted to the command being processed that have not yet
+    // elapsed, counted in calls of `send_wait_extension`.
+    wait_credit: u8,
     #[allow(dead_code)]
     packet_len: usize,
     receiving_long: bool,
+    // The long message being received exceeds dwMaxCCIDMessageLength and is discarded.
+    discarding_long: bool,
     long_packet_missing: usize,
     in_chain: usize,
     pub(crate) started_processing: bool,
-    atr: Vec<u8, 32>,
-    // The sequence number of the last bulk command if it was an abort command.
-    bulk_abort: Option<u8>,
-    // The sequence number of the last abort command received over the control pipe, if any.
-    control_abort: Option<u8>,
+    packet_tap: Option<&'static mut PacketTap>,
+    escape_handler: Option<EscapeHandler>,
+    // The abData of the RDR_to_PC_Escape being sent.
+    escape_response: Vec<u8, MSG>,
+    last_response_was_error: bool,
+    last_error: Option<CcidError>,
+    // The number of transfers dropped by `reset_after`, wrapping.
+    reset_count: u32,
+    last_reset_reason: Option<ResetReason>,
+    last_write_error: Option<UsbError>,
+    stats: PipeStats,
+    seq_policy: SeqPolicy,
+    // The sequence number the next command should carry, unknown after a reset.
+    expected_seq: Option<u8>,
+    ready: bool,
+    // Whether GetParameters requires a powered ICC.
+    parameters_require_power: bool,
 }
 
-impl<'bus, 'pipe, Bus, const N: usize> Pipe<'bus, 'pipe, Bus, N>
+impl<'bus, 'pipe, Bus, const N: usize, const MSG: usize> Pipe<'bus, 'pipe, Bus, N, MSG>
 where
     Bus: 'static + UsbBus,
 {
+    // The configured dwMaxCCIDMessageLength must hold at least one full packet.
+    const MSG_FITS_PACKET: () = assert!(MSG >= PACKET_SIZE);
+
     pub(crate) fn new(
         write: EndpointIn<'bus, Bus>,
+        interrupt: Option<EndpointIn<'bus, Bus>>,
         request_pipe: Requester<'pipe, N>,
         card_issuers_data: Option<&[u8]>,
+        protocol: Protocol,
+        max_message_length: usize,
+        wait_extension_multiplier: u8,
     ) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::MSG_FITS_PACKET;
+        let atr = Self::construct_atr(card_issuers_data, protocol, false);
         Self {
             write,
-            seq: 0,
-            state: State::Idle,
-            sent: 0,
-            outbox: None,
+            interrupt,
+            slots: core::array::from_fn(|_| Slot::new(atr.clone(), protocol)),
+            num_slots: 1,
+            current: 0,
+            protocol,
+            fast_timing: false,
+            t0_segment: Vec::new(),
+            t0_offset: 0,
+            t0_more: false,
             interchange: request_pipe,
+            streaming: false,
+            stream: Deque::new(),
+            stream_complete: false,
+            stream_requested: false,
 
             ext_packet: Default::default(),
+            max_message_length,
+            wait_extension_multiplier,
+            processing_timeout: None,
+            processing_ticks: 0,
+            secure: false,
+            pin_support: PIN_SUPPORT,
+            command_bwi: 0,
+            wait_credit: 0,
             packet_len: 0,
             receiving_long: false,
+            discarding_long: false,
             long_packet_missing: 0,
             in_chain: 0,
             started_processing: false,
-            // later on, we only signal T=1 support
-            // if for some reason not signaling T=0 support leads to issues,
-            // we can enable it here.
-            atr: Self::construct_atr(card_issuers_data, false),
-            bulk_abort: None,
-            control_abort: None,
+            packet_tap: None,
+            escape_handler: None,
+            escape_response: Vec::new(),
+            last_response_was_error: false,
+            last_error: None,
+            reset_count: 0,
+            last_reset_reason: None,
+            last_write_error: None,
+            stats: PipeStats::default(),
+            ready: true,
+            parameters_require_power: false,
+            seq_policy: SeqPolicy::default(),
+            expected_seq: None,
+        }
+    }
+
+    fn slot(&self) -> &Slot {
+        &self.slots[self.current]
+    }
+
+    fn slot_mut(&mut self) -> &mut Slot {
+        &mut self.slots[self.current]
+    }
+
+    /// Set the number of slots announced to the host, between 1 and `MAX_SLOTS`.
+    pub fn set_num_slots(&mut self, num_slots: u8) -> Result<(), ConfigError> {
+        if num_slots == 0 || num_slots as usize > MAX_SLOTS {
+            return Err(ConfigError::UnsupportedSlotCount);
+        }
+        self.num_slots = num_slots;
+        self.current = 0;
+        Ok(())
+    }
+
+    /// The number of slots announced to the host.
+    pub fn num_slots(&self) -> u8 {
+        self.num_slots
+    }
+
+    /// The protocol announced to the host.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Give up on a command once the application did not respond within `ticks` calls of
+    /// `send_wait_extension`, or never (`None`, the default).
+    pub fn set_processing_timeout(&mut self, ticks: Option<u32>) {
+        self.processing_timeout = ticks;
+    }
+
+    /// Set the BWT multiplier of time extensions, clamped to 1..=`MAX_WAIT_EXTENSION_MULTIPLIER`.
+    pub fn set_wait_extension_multiplier(&mut self, multiplier: u8) {
+        self.wait_extension_multiplier = multiplier.clamp(1, MAX_WAIT_EXTENSION_MULTIPLIER);
+    }
+
+    /// Let the application push responses with `stream_response` instead of the interchange.
+    pub fn set_streaming(&mut self, streaming: bool) {
+        self.streaming = streaming;
+        self.reset_stream();
+    }
+
+    /// Streaming mode: push the next bytes of the response to the command being processed,
+    /// `last` marking its end.  Returns the number of bytes accepted, fewer than `data.len()`
+    /// while the host has not fetched the blocks buffered before.
+    pub fn stream_response(&mut self, data: &[u8], last: bool) -> usize {
+        if !self.streaming || self.stream_complete {
+            return 0;
         }
+        let Some(index) = self.interchange_owner() else {
+            return 0;
+        };
+        if !matches!(
+            self.slots[index].state,
+            State::Processing | State::ReadyToSend | State::Sending
+        ) {
+            return 0;
+        }
+
+        let mut accepted = 0;
+        for &byte in data {
+            if self.stream.push_back(byte).is_err() {
+                break;
+            }
+            accepted += 1;
+        }
+        if last && accepted == data.len() {
+            self.stream_complete = true;
+        }
+        // a block that the host asked for may be complete now
+        if self.stream_requested {
+            self.current = index;
+            self.prime_outbox();
+        }
+        accepted
+    }
+
+    fn reset_stream(&mut self) {
+        self.stream.clear();
+        self.stream_complete = false;
+        self.stream_requested = false;
+    }
+
+    // Streaming mode: whether enough of the response was pushed to send the next block, i.e. to
+    // know whether other blocks follow it.
+    fn stream_block_ready(&self) -> bool {
+        self.stream_complete || self.stream.len() > STREAM_BLOCK_LEN
+    }
+
+    /// Forward the PIN operations selected by the bPINSupport bits to the application.
+    pub fn set_pin_support(&mut self, pin_support: u8) {
+        self.pin_support = pin_support;
+    }
+
+    /// The advertised bPINSupport.
+    pub fn pin_support(&self) -> u8 {
+        self.pin_support
+    }
+
+    /// Report `parameters` to GetParameters until the host sets others, if the protocol is T=1.
+    pub fn set_t1_parameters(&mut self, parameters: T1Parameters) {
+        if self.protocol != Protocol::T1 {
+            return;
+        }
+        for slot in &mut self.slots {
+            slot.parameters = Vec::from_slice(&parameters.to_bytes())
+                .expect("protocol parameters have at most 7 bytes");
+        }
+    }
+
+    /// The protocol parameters of `slot`: the defaults until the host sets others.
+    pub fn parameters(&self, slot: u8) -> Result<Parameters, ConfigError> {
+        if slot >= self.num_slots {
+            return Err(ConfigError::InvalidSlot);
+        }
+        let parameters = &self.slots[slot as usize].parameters;
+        Ok(match self.protocol {
+            Protocol::T0 => Parameters::T0(
+                parameters[..]
+                    .try_into()
+                    .expect("T=0 parameters have 5 bytes"),
+            ),
+            Protocol::T1 => Parameters::T1(
+                <[u8; 7]>::try_from(&parameters[..])
+                    .expect("T=1 parameters have 7 bytes")
+                    .into(),
+            ),
+        })
+    }
+
+    /// Announce the Fi/Di of [`AtrBuilder::fast_timing`] in the ATRs, rebuilt with
+    /// `card_issuers_data`, and in the protocol parameters.
+    pub(crate) fn set_fast_timing(&mut self, card_issuers_data: Option<&[u8]>) {
+        self.fast_timing = true;
+        let atr = Self::construct_atr(card_issuers_data, self.protocol, true);
+        let fi_di = AtrBuilder::new().fast_timing().fi_di();
+        for slot in &mut self.slots {
+            slot.atr = atr.clone();
+            // bmFindexDindex comes first for both protocols
+            slot.parameters[0] = fi_di;
+        }
+    }
+
+    /// The advertised dwMaxCCIDMessageLength.
+    pub fn max_message_length(&self) -> usize {
+        self.max_message_length
+    }
+
+    /// Rebuild the ATR of `slot` with the given card issuer's data.
+    pub fn set_card_issuers_data(
+        &mut self,
+        slot: u8,
+        card_issuers_data: Option<&[u8]>,
+    ) -> Result<(), ConfigError> {
+        check_card_issuers_data(card_issuers_data)?;
+        let atr = Self::construct_atr(card_issuers_data, self.protocol, self.fast_timing);
+        if slot >= self.num_slots {
+            return Err(ConfigError::InvalidSlot);
+        }
+        let slot = &mut self.slots[slot as usize];
+        if slot.state != State::Idle {
+            return Err(ConfigError::SlotBusy);
+        }
+        slot.atr = atr;
+        Ok(())
+    }
+
+    /// Replace the ATR of `slot`, e.g. with one built by `AtrBuilder`.
+    pub fn set_atr(&mut self, slot: u8, atr: &[u8]) -> Result<(), ConfigError> {
+        let atr = Vec::from_slice(atr).map_err(|_| ConfigError::AtrTooLong)?;
+        if slot >= self.num_slots {
+            return Err(ConfigError::InvalidSlot);
+        }
+        let slot = &mut self.slots[slot as usize];
+        if slot.state != State::Idle {
+            return Err(ConfigError::SlotBusy);
+        }
+        slot.atr = atr;
+        Ok(())
+    }
+
+    /// Record whether an ICC is present in `slot`, and notify the host if this changed.
+    ///
+    /// Without an interrupt endpoint, only the recorded state is updated.
+    pub fn notify_slot_change(&mut self, slot: u8, present: bool) -> Result<(), ConfigError> {
+        if slot >= self.num_slots {
+            return Err(ConfigError::InvalidSlot);
+        }
+        let slot = &mut self.slots[slot as usize];
+        match (present, slot.icc_status) {
+            (false, _) => slot.icc_status = IccStatus::NotPresent,
+            // a newly inserted ICC waits for PowerOn
+            (true, IccStatus::NotPresent) => slot.icc_status = IccStatus::Inactive,
+            (true, _) => {}
+        }
+        self.maybe_send_notification();
+        Ok(())
+    }
+
+    /// Notify the host of a change of the card in `slot` over the interrupt endpoint.
+    ///
+    /// Insertion and removal are handled like [`notify_slot_change`](Self::notify_slot_change).
+    /// A hardware error deactivates the ICC, which the host has to power on again.
+    pub fn on_card_event(&mut self, slot: u8, event: CardEvent) -> Result<(), ConfigError> {
+        if self.interrupt.is_none() {
+            return Err(ConfigError::NoInterruptEndpoint);
+        }
+        match event {
+            CardEvent::Inserted => self.notify_slot_change(slot, true),
+            CardEvent::Removed => self.notify_slot_change(slot, false),
+            CardEvent::HardwareError(code) => {
+                if slot >= self.num_slots {
+                    return Err(ConfigError::InvalidSlot);
+                }
+                let slot = &mut self.slots[slot as usize];
+                slot.powered = false;
+                if slot.present() {
+                    slot.icc_status = IccStatus::Inactive;
+                }
+                slot.hardware_error = Some(code);
+                self.maybe_send_notification();
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the bmICCStatus reported for `slot`, e.g. to model a removable card.
+    ///
+    /// PowerOn and PowerOff update the status of a present ICC.  Changes of the presence are
+    /// notified like with [`notify_slot_change`](Self::notify_slot_change).
+    pub fn set_icc_status(&mut self, slot: u8, status: IccStatus) -> Result<(), ConfigError> {
+        if slot >= self.num_slots {
+            return Err(ConfigError::InvalidSlot);
+        }
+        self.slots[slot as usize].icc_status = status;
+        self.maybe_send_notification();
+        Ok(())
+    }
+
+    /// Send RDR_to_PC_NotifySlotChange if the presence of an ICC changed since the last one.
+    ///
+    /// Changes made while the previous notification is still pending are coalesced, and
+    /// toggles that end in the reported state are not sent at all.
+    pub fn maybe_send_notification(&mut self) {
+        if self.interrupt.is_none() {
+            return;
+        }
+        let slots = &self.slots[..self.num_slots as usize];
+        if slots
+            .iter()
+            .any(|slot| slot.present() != slot.reported_present)
+        {
+            // bmSlotICCState: two bits per slot, ICC present and change
+            let mut message = [0u8; 1 + MAX_SLOTS.div_ceil(4)];
+            message[0] = 0x50;
+            for (index, slot) in slots.iter().enumerate() {
+                let changed = slot.present() != slot.reported_present;
+                let bits = u8::from(slot.present()) | (u8::from(changed) << 1);
+                message[1 + index / 4] |= bits << (2 * (index % 4));
+            }
+            if !self.write_notification(&message) {
+                return;
+            }
+            for slot in &mut self.slots {
+                slot.reported_present = slot.present();
+            }
+        }
+
+        for index in 0..self.num_slots as usize {
+            let slot = &self.slots[index];
+            let Some(code) = slot.hardware_error else {
+                continue;
+            };
+            // bMessageType, bSlot, bSeq, bHardwareErrorCode
+            let message = [0x51, index as u8, slot.seq, code];
+            if !self.write_notification(&message) {
+                return;
+            }
+            self.slots[index].hardware_error = None;
+        }
+    }
+
+    // Write a message to the interrupt endpoint, returning whether it went out.
+    fn write_notification(&mut self, message: &[u8]) -> bool {
+        let Some(interrupt) = self.interrupt.as_ref() else {
+            return false;
+        };
+        match interrupt.write(message) {
+            Ok(_) => {
+                if let Some(tap) = self.packet_tap.as_mut() {
+                    tap(UsbDirection::In, message);
+                }
+                true
+            }
+            Err(UsbError::WouldBlock) => {
+                // retried once the pending notification went out
+                info!("waiting to send notification");
+                false
+            }
+            Err(_err) => {
+                error!("Failed to send notification {:?}", _err);
+                false
+            }
+        }
+    }
+
+    /// Install a callback that observes every raw packet received and sent.
+    ///
+    /// The tap is called from `handle_packet` (OUT) and from
+    /// `maybe_send_packet` (IN, once the write succeeded).
+    pub fn set_packet_tap(&mut self, tap: Option<&'static mut PacketTap>) {
+        self.packet_tap = tap;
+    }
+
+    /// Select how the sequence numbers of incoming commands are validated.
+    pub fn set_seq_policy(&mut self, policy: SeqPolicy) {
+        self.seq_policy = policy;
+    }
+
+    /// Serialize the effective configuration into `buf`, see `Ccid::config_snapshot`.
+    pub fn config_snapshot(&self, buf: &mut [u8], features: u32) -> usize {
+        const HEADER_LEN: usize = 22;
+        let slot = &self.slots[0];
+        let len = HEADER_LEN + slot.atr.len();
+        if buf.len() < len {
+            return 0;
+        }
+        buf[0] = 1;
+        buf[1] = self.num_slots;
+        buf[2] = self.protocol.number();
+        buf[3..5].copy_from_slice(&(PACKET_SIZE as u16).to_le_bytes());
+        buf[5..9].copy_from_slice(&(self.max_message_length as u32).to_le_bytes());
+        buf[9..13].copy_from_slice(&features.to_le_bytes());
+        buf[13] = self.seq_policy as u8;
+        buf[14..21].fill(0);
+        buf[14..][..slot.parameters.len()].copy_from_slice(&slot.parameters);
+        buf[21] = slot.atr.len() as u8;
+        buf[HEADER_LEN..len].copy_from_slice(&slot.atr);
+        len
+    }
+
+    /// Signal whether the application is ready to process commands.
+    ///
+    /// While not ready, all commands except PowerOn (and Abort) are answered with `CmdSlotBusy`.
+    pub fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+    }
+
+    /// Select whether GetParameters is rejected until the host powered on the ICC.
+    pub fn set_parameters_require_power(&mut self, require: bool) {
+        self.parameters_require_power = require;
+    }
+
+    /// Suggested delay in milliseconds before polling the application again.
+    ///
+    /// `Some` while the application is processing a command, `None` if there is work to do
+    /// (or nothing to wait for).  This is only advisory.
+    pub fn suggested_poll_delay(&self) -> Option<u32> {
+        self.slots
+            .iter()
+            .any(|slot| slot.state == State::Processing)
+            .then_some(PROCESSING_POLL_DELAY_MS)
+    }
+
+    /// Send the ATR with the current sequence number outside of the PowerOn flow.
+    ///
+    /// The ATR is sent for the slot addressed by the last command.  Only possible while no
+    /// transfer is in progress on that slot (`Idle`), returns whether the ATR was sent.
+    pub fn send_atr_now(&mut self) -> bool {
+        if self.slot().state != State::Idle {
+            return false;
+        }
+        self.send_atr();
+        true
+    }
+
+    /// Discard a partially received message, e.g. after the host cleared a halt of the bulk
+    /// OUT endpoint.  The configuration is preserved.
+    pub fn bulk_out_reset(&mut self) {
+        self.ext_packet.clear();
+        self.packet_len = 0;
+        self.receiving_long = false;
+        self.discarding_long = false;
+        self.long_packet_missing = 0;
+        self.in_chain = 0;
+        if let Some(slot) = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.state == State::Receiving)
+        {
+            slot.state = State::Idle;
+            self.reset_interchange();
+        }
+    }
+
+    /// Drop the transfer in progress because of `reason`, recording it for telemetry.
+    pub fn reset_after(&mut self, reason: ResetReason) {
+        self.reset_count = self.reset_count.wrapping_add(1);
+        self.last_reset_reason = Some(reason);
+        self.reset_state();
+    }
+
+    /// The number of transfers dropped because of malformed or unexpected input, wrapping.
+    pub fn reset_count(&self) -> u32 {
+        self.reset_count
+    }
+
+    /// The reason of the most recent dropped transfer not taken yet.
+    pub fn take_last_reset_reason(&mut self) -> Option<ResetReason> {
+        self.last_reset_reason.take()
+    }
+
+    /// The counters accumulated since construction or the last `reset_stats`.
+    pub fn stats(&self) -> PipeStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = PipeStats::default();
+    }
+
+    /// The most recent error reported to the host, if any.
+    pub fn last_error(&self) -> Option<CcidError> {
+        self.last_error
+    }
+
+    /// Whether the most recent response was a slot status error.
+    pub fn last_response_was_error(&self) -> bool {
+        self.last_response_was_error
+    }
+
+    /// Install a handler for PC_to_RDR_Escape commands.
+    ///
+    /// Without a handler, Escape commands are answered with `CommandNotSupported`.
+    pub fn set_escape_handler(&mut self, handler: Option<EscapeHandler>) {
+        self.escape_handler = handler;
     }
 
     /// Reset the state of the CCID driver
     ///
     /// This is done on unexpected input instead of panicking
     pub fn reset_state(&mut self) {
-        self.seq = 0;
-        self.state = State::Idle;
-        self.sent = 0;
-        self.outbox = None;
+        for slot in &mut self.slots {
+            slot.reset();
+        }
         self.packet_len = 0;
         self.receiving_long = false;
+        self.discarding_long = false;
         self.long_packet_missing = 0;
         self.in_chain = 0;
         self.started_processing = false;
-        self.bulk_abort = None;
-        self.control_abort = None;
+        self.expected_seq = None;
+        self.t0_more = false;
+        self.reset_stream();
         self.reset_interchange();
     }
 
-    fn construct_atr(card_issuers_data: Option<&[u8]>, signal_t_equals_0: bool) -> Vec<u8, 32> {
-        assert!(card_issuers_data.map_or(true, |data| data.len() <= 13));
-        let k = card_issuers_data.map_or(0u8, |data| 2 + data.len() as u8);
-        let mut atr = Vec::new();
-        // TS: direct convention
-        atr.push(0x3B).ok();
-        // T0: encode length of historical bytes
-        atr.push(0x80 | k).ok();
-        if signal_t_equals_0 {
-            // T=0, more to follow
-            atr.push(0x80).ok();
-        }
-        // T=1
-        atr.push(0x01).ok();
+    // The card issuer's data has been validated with `check_card_issuers_data`.
+    fn construct_atr(
+        card_issuers_data: Option<&[u8]>,
+        protocol: Protocol,
+        fast_timing: bool,
+    ) -> Vec<u8, MAX_ATR_LEN> {
+        atr_builder(card_issuers_data, protocol, fast_timing)
+            .build()
+            .expect("card issuer's data fits into the ATR")
+    }
 
-        if let Some(data) = card_issuers_data {
-            // no status indicator
-            atr.push(0x80).ok();
-            // tag 5: card issuer's data
-            atr.push(0x50 | data.len() as u8).ok();
-            atr.extend_from_slice(data).ok();
-        }
-        // xor of all bytes except TS
-        let mut checksum = 0;
-        for byte in atr.iter().skip(1) {
-            checksum ^= *byte;
+    // Selects the slot addressed by a command.  Commands for slots that do not exist are
+    // rejected with `BadSlot`, queued behind the responses of the previously addressed slot.
+    fn select_slot(&mut self, slot: u8, seq: u8) -> bool {
+        if slot < self.num_slots {
+            self.current = slot as usize;
+            return true;
         }
-        atr.push(checksum).ok();
+        info!("command for non-existent slot {}", slot);
+        let message_type = response_type(self.ext_packet[0]);
+        self.send_error_to(
+            message_type,
+            slot,
+            seq,
+            IccStatus::NotPresent,
+            CcidError::BadSlot,
+        );
+        false
+    }
 
-        atr
+    // Swallows a packet of a long message that is rejected with `CmdAborted` once complete.
+    fn discard_long_packet(&mut self, len: usize) {
+        self.long_packet_missing = self.long_packet_missing.saturating_sub(len);
+        // a short packet terminates the bulk transfer, even if the host lied about the length
+        if self.long_packet_missing == 0 || len < PACKET_SIZE {
+            self.receiving_long = false;
+            self.discarding_long = false;
+            let (slot, seq) = (self.ext_packet[5], self.ext_packet[6]);
+            if self.select_slot(slot, seq) {
+                self.slot_mut().seq = seq;
+                self.send_error(CcidError::CmdAborted);
+            }
+        }
     }
 
     pub fn handle_packet(&mut self, packet: RawPacket) {
         use crate::types::packet::RawPacketExt;
 
+        if let Some(tap) = self.packet_tap.as_mut() {
+            tap(UsbDirection::Out, &packet);
+        }
+        self.stats.bytes_received = self.stats.bytes_received.wrapping_add(packet.len() as u32);
+
+        // A ZLP ends a transfer of full packets or is a stray from a flaky hub; it carries
+        // nothing, so it must not disturb a transfer in progress.
+        if packet.is_empty() {
+            info!("ignoring ZLP");
+            return;
+        }
+
         // SHOULD CLEAN THIS UP!
         // The situation is as follows: full 64B USB packet received.
         // CCID packet signals no command chaining, but data length > 64 - 10.
@@ -159,7 +916,7 @@ where
         if !self.receiving_long {
             if packet.len() < CCID_HEADER_LEN {
                 error!("unexpected short packet");
-                self.reset_state();
+                self.reset_after(ResetReason::ShortPacket);
                 return;
             }
             self.ext_packet.clear();
@@ -168,33 +925,77 @@ where
                 .extend_from_slice(&packet)
                 .expect("Raw packets are not larger than ext packets");
 
-            let pl = packet.data_len();
+            let Some(pl) = packet.data_len() else {
+                error!("unexpected short packet");
+                self.reset_after(ResetReason::ShortPacket);
+                return;
+            };
             if pl > PACKET_SIZE - CCID_HEADER_LEN {
+                // only a full packet is continued by the next one
+                if packet.len() != PACKET_SIZE {
+                    error!(
+                        "Message length {} does not match dwLength {}",
+                        packet.len() - CCID_HEADER_LEN,
+                        pl
+                    );
+                    self.reset_after(ResetReason::LengthMismatch);
+                    return;
+                }
                 self.receiving_long = true;
+                // The host must not exceed the dwMaxCCIDMessageLength we advertise.  Swallow the
+                // rest of the message and reject it once it is complete.
+                self.discarding_long = pl > self.max_message_length - CCID_HEADER_LEN;
+                if self.discarding_long {
+                    error!("Message exceeds dwMaxCCIDMessageLength: {}", pl);
+                }
                 self.in_chain = 1;
                 self.long_packet_missing = pl - (PACKET_SIZE - CCID_HEADER_LEN);
                 self.packet_len = pl;
                 return;
             }
+            // the data accessors rely on dwLength, the message must be exactly that long
+            if packet.len() != CCID_HEADER_LEN + pl {
+                error!(
+                    "Message length {} does not match dwLength {}",
+                    packet.len() - CCID_HEADER_LEN,
+                    pl
+                );
+                self.reset_after(ResetReason::LengthMismatch);
+                return;
+            }
+        } else if self.discarding_long {
+            self.discard_long_packet(packet.len());
+            return;
         } else {
-            // TODO check
-            if self.ext_packet.extend_from_slice(&packet).is_err() {
+            // bytes beyond the declared length are not part of the message
+            let len = if packet.len() > self.long_packet_missing {
+                error!(
+                    "Got larger packet than expected, dropping {} bytes",
+                    packet.len() - self.long_packet_missing
+                );
+                self.long_packet_missing
+            } else {
+                packet.len()
+            };
+            if self.ext_packet.extend_from_slice(&packet[..len]).is_err() {
                 error!(
                     "Extended packet got larger than maximum size ({}), wants {}",
                     self.ext_packet.capacity(),
                     self.ext_packet.len() + packet.len(),
                 );
-                self.reset_state();
+                // swallow the rest of the message and reject it once it is complete
+                self.discarding_long = true;
+                self.discard_long_packet(packet.len());
                 return;
             }
             self.in_chain += 1;
-            if packet.len() > self.long_packet_missing {
-                error!("Got larger packet than expected");
-                self.long_packet_missing = 0;
-            } else {
-                self.long_packet_missing -= packet.len();
-            }
+            self.long_packet_missing -= len;
             if self.long_packet_missing != 0 {
+                // a short packet terminates the bulk transfer, the message will not complete
+                if packet.len() < PACKET_SIZE {
+                    error!("Message ended {} bytes early", self.long_packet_missing);
+                    self.reset_after(ResetReason::ShortPacket);
+                }
                 return;
             }
 
@@ -208,49 +1009,233 @@ where
         // match PacketCommand::try_from(packet) {
         match PacketCommand::try_from(self.ext_packet.clone()) {
             Ok(command) => {
-                self.seq = command.seq();
+                let seq = command.seq();
+                let in_sequence = self.check_seq(seq);
+                if !in_sequence && !self.continues_chain(&command) {
+                    error!("Unexpected sequence number {}", seq);
+                    self.reset_after(ResetReason::UnexpectedSeq);
+                    return;
+                }
+                if !self.select_slot(command.slot(), seq) {
+                    return;
+                }
+                self.slot_mut().seq = seq;
+                // A block of a chained transfer out of sequence drops the transfer and fails,
+                // so that the host starts over.
+                if !in_sequence {
+                    error!("Unexpected sequence number {} within a chain", seq);
+                    self.discard_response();
+                    self.send_error(CcidError::CmdAborted);
+                    return;
+                }
 
                 // If we receive an ABORT on the control pipe, we reject all further commands until
-                // we receive a matching ABORT on the bulk endpoint too.
-                if let Some(control_abort) = self.control_abort {
-                    if matches!(command, PacketCommand::Abort(_)) && control_abort == self.seq {
+                // we receive a matching ABORT on the bulk endpoint too.  Per CCID 5.3.1, this
+                // includes unrelated commands and PC_to_RDR_Abort with another bSeq: they all
+                // fail with CMD_ABORTED, as the host may have queued them before the abort.
+                if let Some(control_abort) = self.slot().control_abort {
+                    if matches!(command, PacketCommand::Abort(_)) && control_abort == seq {
                         self.abort();
                     } else {
-                        self.send_slot_status_error(Error::CmdAborted);
+                        self.send_error(CcidError::CmdAborted);
                     }
                     return;
                 }
-                self.bulk_abort = None;
+                self.slot_mut().bulk_abort = None;
+
+                if !self.ready
+                    && !matches!(command, PacketCommand::PowerOn(_) | PacketCommand::Abort(_))
+                {
+                    info!("not ready, rejecting {:?}", command.command_type());
+                    self.send_error(CcidError::CmdSlotBusy);
+                    return;
+                }
 
                 // happy path
                 match command {
-                    PacketCommand::PowerOn(_command) => self.send_atr(),
+                    PacketCommand::PowerOn(_command) => {
+                        if self.slot().state != State::Idle {
+                            // the host gave up on the transfer and restarts
+                            info!("PowerOn during a transfer, abandoning it");
+                            self.discard_response();
+                            self.abort_transfer();
+                        }
+                        if !self.slot().present() {
+                            info!("PowerOn without ICC");
+                            self.send_data_block_error(CcidError::IccMute as u8);
+                            return;
+                        }
+                        let slot = self.slot_mut();
+                        slot.powered = true;
+                        slot.icc_status = IccStatus::Active;
+                        self.send_atr();
+                    }
 
-                    PacketCommand::PowerOff(_command) => self.send_slot_status_ok(),
+                    PacketCommand::PowerOff(_command) => {
+                        let slot = self.slot_mut();
+                        slot.powered = false;
+                        if slot.present() {
+                            slot.icc_status = IccStatus::Inactive;
+                        }
+                        self.send_slot_status_ok();
+                    }
 
                     PacketCommand::GetSlotStatus(_command) => self.send_slot_status_ok(),
 
                     PacketCommand::XfrBlock(command) => self.handle_transfer(command),
 
-                    PacketCommand::Abort(_command) => self.bulk_abort = Some(self.seq),
+                    PacketCommand::Abort(_command) => {
+                        match self.slot().state {
+                            State::Receiving => {
+                                // the host will not continue the chain, drop the partial command
+                                info!("Abort while receiving, dropping partial command");
+                                self.slot_mut().state = State::Idle;
+                                self.reset_interchange();
+                            }
+                            State::ReadyToSend | State::Sending | State::ResponsePending => {
+                                // stop sending blocks the host will not fetch anymore
+                                info!("Abort while sending, dropping response");
+                                self.discard_response();
+                            }
+                            _ => {}
+                        }
+                        self.slot_mut().bulk_abort = Some(seq);
+                    }
+
+                    PacketCommand::SetParameters(command) => self.set_parameters(command),
+
+                    PacketCommand::Escape(command) => self.handle_escape(command),
+
+                    PacketCommand::Secure(command) => self.handle_secure(command),
+
+                    PacketCommand::SetDataRateAndClockFrequency(command) => {
+                        self.set_data_rate_and_clock_frequency(command)
+                    }
+
+                    // With APDU level exchange, the host issues GET RESPONSE and ENVELOPE itself,
+                    // so the class bytes it configures here are never needed.
+                    PacketCommand::T0Apdu(_command) => {
+                        info!(
+                            "T0APDU: changes {:X}, GET RESPONSE class {:X}, ENVELOPE class {:X}",
+                            _command.changes(),
+                            _command.class_get_response(),
+                            _command.class_envelope()
+                        );
+                        self.send_slot_status_ok();
+                    }
+
+                    // There is no mechanism to drive, the defined functions are no-ops.
+                    PacketCommand::Mechanical(command) => {
+                        if (1..=5).contains(&command.function()) {
+                            self.send_slot_status_ok();
+                        } else {
+                            info!("Mechanical: unknown function {}", command.function());
+                            self.send_slot_status_error(CcidError::BadFunction);
+                        }
+                    }
 
-                    PacketCommand::GetParameters(_command) => self.send_parameters(),
+                    PacketCommand::GetParameters(_command) => {
+                        if self.parameters_require_power && !self.slot().powered {
+                            info!("GetParameters without powered ICC");
+                            self.send_error(CcidError::IccMute);
+                        } else {
+                            self.send_parameters();
+                        }
+                    }
                 }
             }
 
             Err(PacketError::ShortPacket) => {
                 error!("Unexpectedly short packet");
-                self.reset_state();
+                self.reset_after(ResetReason::ShortPacket);
+            }
+
+            Err(PacketError::MessageTooLong) => {
+                error!("Message does not fit");
+                self.reset_after(ResetReason::MessageTooLong);
             }
 
             Err(PacketError::UnknownCommand(_p)) => {
                 info!("unknown command {:X?}", &_p);
-                self.seq = self.ext_packet[6];
-                self.send_slot_status_error(Error::CommandNotSupported);
+                let (slot, seq) = (self.ext_packet[5], self.ext_packet[6]);
+                self.expected_seq = Some(seq.wrapping_add(1));
+                if self.select_slot(slot, seq) {
+                    self.slot_mut().seq = seq;
+                    self.send_error(CcidError::CommandNotSupported);
+                }
             }
         }
     }
 
+    // Drops the response that is being sent, including packets not yet written.
+    fn discard_response(&mut self) {
+        let slot = self.slot_mut();
+        slot.state = State::Idle;
+        slot.sent = 0;
+        slot.outbox.clear();
+        slot.escape_sent = None;
+        self.t0_more = false;
+        self.reset_stream();
+        self.reset_interchange();
+    }
+
+    // T=0: copies the next segment of the response into `t0_segment`.  Segments with more data
+    // following end with SW 61XX, the last one with the status word of the response.
+    fn next_t0_segment(&mut self) {
+        self.t0_segment.clear();
+        self.t0_more = false;
+        let Ok(response) = self.interchange.response() else {
+            return;
+        };
+        let (data, sw) = response.split_at(response.len().saturating_sub(2));
+        let rest = &data[self.t0_offset.min(data.len())..];
+        if rest.len() > T0_RESPONSE_CHUNK {
+            let available = rest.len() - T0_RESPONSE_CHUNK;
+            self.t0_segment
+                .extend_from_slice(&rest[..T0_RESPONSE_CHUNK])
+                .ok();
+            // SW2 = 0 means 256 or more bytes available
+            let sw2 = if available > 0xff { 0 } else { available as u8 };
+            self.t0_segment.extend_from_slice(&[0x61, sw2]).ok();
+            self.t0_offset += T0_RESPONSE_CHUNK;
+            self.t0_more = true;
+        } else {
+            self.t0_segment.extend_from_slice(rest).ok();
+            self.t0_segment.extend_from_slice(sw).ok();
+            self.t0_offset = data.len();
+        }
+    }
+
+    // Checks an incoming sequence number against the configured policy and records the next
+    // expected one.
+    fn check_seq(&mut self, seq: u8) -> bool {
+        let expected = self.expected_seq.replace(seq.wrapping_add(1));
+        match (self.seq_policy, expected) {
+            (SeqPolicy::Strict, Some(expected)) => seq == expected,
+            _ => true,
+        }
+    }
+
+    // Whether a command is the next block of a chained transfer of its slot.
+    fn continues_chain(&self, command: &PacketCommand<MSG>) -> bool {
+        matches!(command, PacketCommand::XfrBlock(_))
+            && self
+                .slots
+                .get(command.slot() as usize)
+                .is_some_and(|slot| matches!(slot.state, State::Receiving | State::Sending))
+    }
+
+    /// The state of the transfer in progress, or `Idle` if there is none.
+    pub fn state(&self) -> State {
+        self.interchange_owner()
+            .map_or(State::Idle, |index| self.slots[index].state)
+    }
+
+    // The slot whose transfer currently uses the interchange, if any.
+    fn interchange_owner(&self) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.state != State::Idle)
+    }
+
     #[inline(never)]
     fn reset_interchange(&mut self) {
         let message = Vec::new();
@@ -264,109 +1249,172 @@ where
         self.interchange.take_response();
     }
 
-    fn handle_transfer(&mut self, command: XfrBlock) {
+    fn handle_transfer(&mut self, command: XfrBlock<MSG>) {
         // state: Idle, Receiving, Processing, Sending,
         //
         // conts: BeginsAndEnds, Begins, Ends, Continues, ExpectDataBlock,
 
         // info!("handle xfrblock").ok();
         // info!("{:X?}", &command);
-        match self.state {
+        let state = self.slot().state;
+        match state {
+            // There is a single interchange, so only one slot can have a transfer in progress.
+            State::Idle if self.interchange_owner().is_some() => {
+                info!("interchange busy with another slot");
+                self.send_error(CcidError::CmdSlotBusy);
+            }
+
+            State::ResponsePending if matches!(command.data(), [_, 0xc0, ..]) => {
+                info!("GET RESPONSE");
+                self.next_t0_segment();
+                let slot = self.slot_mut();
+                slot.state = State::ReadyToSend;
+                slot.sent = 0;
+                self.prime_outbox();
+            }
+            State::ResponsePending => {
+                // the host moved on without fetching the rest of the response
+                info!("dropping unfetched response");
+                self.discard_response();
+                self.handle_transfer(command);
+            }
+
             State::Idle => {
                 // invariant: BUFFER_SIZE >= PACKET_SIZE
                 match command.chain() {
                     Ok(Chain::BeginsAndEnds) => {
                         info!("begins and ends");
+                        if command.data().is_empty() {
+                            // an empty command is not a valid APDU, don't bother the app
+                            error!("Empty command");
+                            self.send_wrong_length();
+                            return;
+                        }
                         self.reset_interchange();
                         let Ok(message) = self.interchange.request_mut() else {
-                            error!("Interchange is busy");
-                            self.reset_state();
+                            self.reject_busy_interchange();
                             return;
                         };
                         message.clear();
                         if message.extend_from_slice(command.data()).is_err() {
                             error!("Interchange is full");
-                            self.reset_state();
+                            self.send_wrong_length();
                             return;
                         };
                         self.call_app();
-                        self.state = State::Processing;
+                        self.set_command_bwi(command.bwi());
+                        self.slot_mut().state = State::Processing;
                         // self.send_empty_datablock();
                     }
                     Ok(Chain::Begins) => {
                         info!("begins");
                         self.reset_interchange();
                         let Ok(message) = self.interchange.request_mut() else {
-                            error!("Interchange is busy");
-                            self.reset_state();
+                            self.reject_busy_interchange();
                             return;
                         };
                         message.clear();
-                        if message.extend_from_slice(command.data()).is_err() {
-                            error!("Interchange is full");
-                            self.reset_state();
-                            return;
-                        };
-                        self.state = State::Receiving;
+                        // The chain carries no total length, but the APDU header declares Lc:
+                        // a command that cannot fit is not collected at all.
+                        let discarding = declared_apdu_len(command.data()).unwrap_or(0) > N
+                            || message.extend_from_slice(command.data()).is_err();
+                        if discarding {
+                            error!("Command does not fit into the interchange");
+                        }
+                        // CCID 6.1.4: every XfrBlock is answered before the host may send the
+                        // next one, so this acknowledgement is required even if the data turns
+                        // out to be complete and the host follows up with an empty Ends.
+                        let slot = self.slot_mut();
+                        slot.state = State::Receiving;
+                        slot.discarding_chain = discarding;
                         self.send_empty_datablock(Chain::ExpectingMore);
                     }
                     Err(_) => {
                         error!("Unknown chain");
-                        self.reset_state();
+                        self.reset_after(ResetReason::UnknownChain);
                     }
                     _ => {
                         error!("unexpectedly in idle state");
-                        self.reset_state();
+                        self.reset_after(ResetReason::UnexpectedCommand);
                     }
                 }
             }
 
+            // The host sends the whole chain before it reads the answer, so an oversized command
+            // is only rejected once the chain ends.
+            State::Receiving if self.slot().discarding_chain => match command.chain() {
+                Ok(Chain::Continues) => self.send_empty_datablock(Chain::ExpectingMore),
+                Ok(Chain::Ends) => self.send_wrong_length(),
+                Err(_) => {
+                    error!("Unknown chain");
+                    self.reset_after(ResetReason::UnknownChain);
+                }
+                _ => {
+                    error!("unexpectedly in receiving state");
+                    self.reset_after(ResetReason::UnexpectedCommand);
+                }
+            },
+
             State::Receiving => match command.chain() {
                 Ok(Chain::Continues) => {
                     info!("continues");
                     let Ok(message) = self.interchange.request_mut() else {
-                        error!("Interchange is busy");
-                        self.reset_state();
+                        self.reject_busy_interchange();
                         return;
                     };
                     if message.extend_from_slice(command.data()).is_err() {
                         error!("Receiving unexpectedly large data");
-                        self.reset_state();
-                        return;
+                        self.slot_mut().discarding_chain = true;
                     }
                     self.send_empty_datablock(Chain::ExpectingMore);
                 }
                 Ok(Chain::Ends) => {
+                    // may be empty if the data of the chain fit into the Begins
                     info!("ends");
                     let Ok(message) = self.interchange.request_mut() else {
-                        error!("Interchange is busy");
-                        self.reset_state();
+                        self.reject_busy_interchange();
                         return;
                     };
                     if message.extend_from_slice(command.data()).is_err() {
                         error!("Receiving unexpectedly large data");
-                        self.reset_state();
+                        self.send_wrong_length();
+                        return;
+                    }
+                    if message.is_empty() {
+                        error!("Empty command");
+                        self.send_wrong_length();
                         return;
                     }
                     self.call_app();
-                    self.state = State::Processing;
+                    self.set_command_bwi(command.bwi());
+                    self.slot_mut().state = State::Processing;
                 }
                 Err(_) => {
                     error!("Unknown chain");
-                    self.reset_state();
+                    self.reset_after(ResetReason::UnknownChain);
                 }
                 _ => {
                     error!("unexpectedly in receiving state");
-                    self.reset_state();
+                    self.reset_after(ResetReason::UnexpectedCommand);
                 }
             },
 
+            // Some hosts poll with empty XfrBlocks as keep-alives during long operations.
+            State::Processing if command.is_response_pull() => {
+                info!("keep-alive while processing");
+                self.send_wait_extension();
+            }
+            State::ReadyToSend if command.is_response_pull() => {
+                info!("keep-alive while ready to send");
+                self.prime_outbox();
+            }
+
             State::Processing | State::ReadyToSend => {
                 error!(
                     "ccid pipe unexpectedly received command {:?} while in state: {:?}",
-                    &command, self.state,
+                    &command, state,
                 );
-                self.reset_state();
+                self.reset_after(ResetReason::UnexpectedCommand);
             }
 
             State::Sending => match command.chain() {
@@ -378,24 +1426,44 @@ where
                         "unexpectedly in receiving state and got chain: {:?}",
                         _chain
                     );
-                    self.reset_state();
+                    self.reset_after(ResetReason::UnexpectedCommand);
                 }
             },
         }
     }
 
     pub fn send_wait_extension(&mut self) -> bool {
-        if self.state == State::Processing {
+        // The response may have arrived since the last poll.  Pick it up first so that we never
+        // request more time once the response is primed.
+        if self.poll_app() == PollResult::StillProcessing {
+            if let Some(timeout) = self.processing_timeout {
+                if self.processing_ticks >= timeout {
+                    error!("Application did not respond in time");
+                    self.give_up_processing(CcidError::IccMute);
+                    return false;
+                }
+                self.processing_ticks += 1;
+            }
+            // The host granted bBWI block waiting times for the command, and as many as the
+            // multiplier with each time extension: no extension is due before they elapsed.
+            if self.wait_credit > 1 {
+                self.wait_credit -= 1;
+                return true;
+            }
             // Need to send a wait extension request.
             let mut packet = RawPacket::zeroed_until(CCID_HEADER_LEN);
             packet[0] = 0x80;
-            packet[6] = self.seq;
+            packet[5] = self.current as u8;
+            packet[6] = self.slot().seq;
 
             // CCID_Rev110 6.2-3: Time Extension is requested
-            packet[7] = 2 << 6;
-            // Perhaps 1 is an ok multiplier?
-            packet[8] = 0x1;
+            packet[7] = (2 << 6) | self.slot().icc_status as u8;
+            packet[8] = self
+                .wait_extension_multiplier
+                .max(self.command_bwi.min(MAX_WAIT_EXTENSION_MULTIPLIER));
+            self.wait_credit = packet[8];
             self.send_packet_assuming_possible(packet);
+            self.stats.wait_extensions = self.stats.wait_extensions.wrapping_add(1);
 
             // Indicate we should check back again for another possible wait extension
             true
@@ -405,6 +1473,39 @@ where
         }
     }
 
+    /// Abandon the command the application is processing and answer it with `CmdAborted`.
+    ///
+    /// Returns whether a command was cancelled.  Commands being received or answered are left
+    /// alone, as the host is not waiting for a response then.
+    pub fn cancel(&mut self) -> bool {
+        let Some(index) = self
+            .slots
+            .iter()
+            .position(|slot| slot.state == State::Processing)
+        else {
+            return false;
+        };
+        self.current = index;
+        info!("cancelling command");
+        self.give_up_processing(CcidError::CmdAborted);
+        true
+    }
+
+    // Cancels the command being processed and lets the host recover.
+    fn give_up_processing(&mut self, error: CcidError) {
+        self.slot_mut().state = State::Idle;
+        self.started_processing = false;
+        self.reset_stream();
+        self.reset_interchange();
+        self.send_data_block_error(error as u8);
+    }
+
+    // Records the bBWI of the command handed to the application.
+    fn set_command_bwi(&mut self, bwi: u8) {
+        self.command_bwi = bwi;
+        self.wait_credit = bwi;
+    }
+
     /// Turns false on read.  Intended for checking to see if a wait extension request needs to be started.
     pub fn did_start_processing(&mut self) -> bool {
         if self.started_processing {
@@ -420,122 +1521,493 @@ where
         self.interchange
             .send_request()
             .expect("could not deposit command");
+        self.stats.apdus = self.stats.apdus.wrapping_add(1);
+        self.processing_ticks = 0;
+        self.reset_stream();
+        self.secure = false;
+        self.command_bwi = 0;
+        self.wait_credit = 0;
         self.started_processing = true;
-        self.state = State::Processing;
+        self.slot_mut().state = State::Processing;
     }
 
+    /// Check whether the application has responded, and if so start sending the response.
     #[inline(never)]
-    pub fn poll_app(&mut self) {
-        if State::Processing == self.state {
-            // info!("processing, checking for response, interchange state {:?}",
-            //           self.interchange.state()).ok();
-
-            if interchange::State::Responded == self.interchange.state() {
-                // we should have an open XfrBlock allowance
-                self.state = State::ReadyToSend;
-                self.sent = 0;
-                self.prime_outbox();
+    pub fn poll_app(&mut self) -> PollResult {
+        let Some(index) = self
+            .slots
+            .iter()
+            .position(|slot| slot.state == State::Processing)
+        else {
+            return PollResult::NotProcessing;
+        };
+        // the response belongs to the slot that sent the command
+        self.current = index;
+        // info!("processing, checking for response, interchange state {:?}",
+        //           self.interchange.state()).ok();
+
+        if self.streaming {
+            if !self.stream_block_ready() {
+                return PollResult::StillProcessing;
             }
+            let slot = self.slot_mut();
+            slot.state = State::ReadyToSend;
+            slot.sent = 0;
+            self.prime_outbox();
+            return PollResult::ResponseReady;
+        }
+
+        if interchange::State::Responded == self.interchange.state() {
+            // A response APDU has at least two bytes (SW1 SW2).  A single byte is the
+            // application requesting a CCID error instead, see `Ccid`.
+            if let Ok(&[error]) = self.interchange.response().map(|response| &response[..]) {
+                info!("application signaled error {:#x}", error);
+                self.interchange.take_response();
+                self.slot_mut().state = State::Idle;
+                self.send_data_block_error(error);
+                return PollResult::ResponseReady;
+            }
+            // we should have an open XfrBlock allowance
+            let slot = self.slot_mut();
+            slot.state = State::ReadyToSend;
+            slot.sent = 0;
+            if self.protocol == Protocol::T0 {
+                self.t0_offset = 0;
+                self.next_t0_segment();
+            }
+            self.prime_outbox();
+            PollResult::ResponseReady
+        } else {
+            PollResult::StillProcessing
         }
     }
 
     pub fn prime_outbox(&mut self) {
-        if self.state != State::ReadyToSend && self.state != State::Sending {
+        let slot = &mut self.slots[self.current];
+        if slot.state != State::ReadyToSend && slot.state != State::Sending {
             return;
         }
 
-        if self.outbox.is_some() {
+        if slot.outbox.is_full() {
             error!("Full outbox");
-            self.reset_state();
+            self.reset_after(ResetReason::OutboxFull);
             return;
         }
 
-        let Ok(message) = self.interchange.response() else {
-            error!("Got no response while priming outbox");
-            self.reset_state();
+        if self.streaming {
+            self.prime_stream();
+            return;
+        }
+
+        let message: &[u8] = if self.protocol == Protocol::T0 {
+            &self.t0_segment
+        } else {
+            let Ok(message) = self.interchange.response() else {
+                error!("Got no response while priming outbox");
+                self.reset_after(ResetReason::MissingResponse);
+                return;
+            };
+            message
+        };
+        // with T=0, the host fetches the rest of a long response with GET RESPONSE
+        let done = if self.t0_more {
+            State::ResponsePending
+        } else {
+            State::Idle
+        };
+
+        let chunk_size = core::cmp::min(PACKET_SIZE - CCID_HEADER_LEN, message.len() - slot.sent);
+        let chunk = &message[slot.sent..][..chunk_size];
+        slot.sent += chunk_size;
+        let more = slot.sent < message.len();
+
+        let Some(chain) = Self::next_chain(slot, more, done) else {
             return;
         };
 
-        let chunk_size = core::cmp::min(PACKET_SIZE - CCID_HEADER_LEN, message.len() - self.sent);
-        let chunk = &message[self.sent..][..chunk_size];
-        self.sent += chunk_size;
-        let more = self.sent < message.len();
+        let primed_packet =
+            DataBlock::new(self.current as u8, slot.seq, slot.icc_status, chain, chunk);
+        // info!("priming {:?}", &primed_packet).ok();
+        slot.outbox.push_back(primed_packet.into()).ok();
+        self.last_response_was_error = false;
 
-        let chain = match (self.state, more) {
+        // fast-lane response attempt
+        self.maybe_send_packet().ok();
+    }
+
+    // Streaming mode: primes the next block of the response once the application pushed it,
+    // otherwise remembers that the host is waiting for it.
+    fn prime_stream(&mut self) {
+        if !self.stream_block_ready() {
+            self.stream_requested = true;
+            return;
+        }
+        self.stream_requested = false;
+        let chunk_size = core::cmp::min(STREAM_BLOCK_LEN, self.stream.len());
+        let chunk: Vec<u8, STREAM_BLOCK_LEN> = (0..chunk_size)
+            .filter_map(|_| self.stream.pop_front())
+            .collect();
+        let more = !self.stream.is_empty();
+
+        let slot = &mut self.slots[self.current];
+        let Some(chain) = Self::next_chain(slot, more, State::Idle) else {
+            return;
+        };
+        let primed_packet =
+            DataBlock::new(self.current as u8, slot.seq, slot.icc_status, chain, &chunk);
+        slot.outbox.push_back(primed_packet.into()).ok();
+        self.last_response_was_error = false;
+
+        self.maybe_send_packet().ok();
+    }
+
+    // Advances the state of a slot sending a response, `more` telling whether other blocks
+    // follow the next one, and returns the chain parameter of that block.
+    fn next_chain(slot: &mut Slot, more: bool, done: State) -> Option<Chain> {
+        let chain = match (slot.state, more) {
             (State::ReadyToSend, true) => {
-                self.state = State::Sending;
+                slot.state = State::Sending;
                 Chain::Begins
             }
             (State::ReadyToSend, false) => {
-                self.state = State::Idle;
+                slot.state = done;
                 Chain::BeginsAndEnds
             }
             (State::Sending, true) => Chain::Continues,
             (State::Sending, false) => {
-                self.state = State::Idle;
+                slot.state = done;
                 Chain::Ends
             }
             // logically impossible
-            _ => {
-                return;
-            }
+            _ => return None,
         };
+        Some(chain)
+    }
 
-        let primed_packet = DataBlock::new(self.seq, chain, chunk);
-        // info!("priming {:?}", &primed_packet).ok();
-        self.outbox = Some(primed_packet.into());
-
-        // fast-lane response attempt
-        self.maybe_send_packet();
+    // The application has not taken the previous request yet.  Drop the command (and the rest
+    // of its chain) and let the host retry it.
+    fn reject_busy_interchange(&mut self) {
+        info!("Interchange is busy");
+        self.slot_mut().state = State::Idle;
+        self.send_data_block_error(CcidError::CmdSlotBusy as u8);
     }
 
     fn send_empty_datablock(&mut self, chain: Chain) {
-        let packet = DataBlock::new(self.seq, chain, &[]).into();
+        let packet = DataBlock::new(
+            self.current as u8,
+            self.slot().seq,
+            self.slot().icc_status,
+            chain,
+            &[],
+        )
+        .into();
+        self.send_packet_assuming_possible(packet);
+    }
+
+    // The command APDU does not fit into the interchange: drop it and answer with SW 6700
+    // (wrong length) instead of leaving the host waiting for a response.
+    fn send_wrong_length(&mut self) {
+        self.reset_interchange();
+        self.slot_mut().state = State::Idle;
+        let packet = DataBlock::new(
+            self.current as u8,
+            self.slot().seq,
+            self.slot().icc_status,
+            Chain::BeginsAndEnds,
+            &[0x67, 0x00],
+        )
+        .into();
+        self.send_packet_assuming_possible(packet);
+    }
+
+    fn send_data_block_error(&mut self, error: u8) {
+        self.last_response_was_error = true;
+        self.last_error = CcidError::try_from(error).ok();
+        let mut packet = RawPacket::zeroed_until(CCID_HEADER_LEN);
+        packet[0] = 0x80;
+        packet[5] = self.current as u8;
+        packet[6] = self.slot().seq;
+        packet[7] = (1 << 6) | self.slot().icc_status as u8;
+        packet[8] = error;
         self.send_packet_assuming_possible(packet);
     }
 
     fn send_slot_status_ok(&mut self) {
+        self.last_response_was_error = false;
         let mut packet = RawPacket::zeroed_until(CCID_HEADER_LEN);
         packet[0] = 0x81;
-        packet[6] = self.seq;
+        packet[5] = self.current as u8;
+        packet[6] = self.slot().seq;
+        packet[7] = self.slot().icc_status as u8;
         self.send_packet_assuming_possible(packet);
     }
 
-    fn send_slot_status_error(&mut self, error: Error) {
+    fn send_slot_status_error(&mut self, error: CcidError) {
+        let (seq, icc_status) = (self.slot().seq, self.slot().icc_status);
+        self.send_error_to(0x81, self.current as u8, seq, icc_status, error);
+    }
+
+    // Reports `error` in the message type answering the command being handled.
+    fn send_error(&mut self, error: CcidError) {
+        let (seq, icc_status) = (self.slot().seq, self.slot().icc_status);
+        let message_type = response_type(self.ext_packet[0]);
+        self.send_error_to(message_type, self.current as u8, seq, icc_status, error);
+    }
+
+    fn send_error_to(
+        &mut self,
+        message_type: u8,
+        slot: u8,
+        seq: u8,
+        icc_status: IccStatus,
+        error: CcidError,
+    ) {
+        self.last_response_was_error = true;
+        self.last_error = Some(error);
         let mut packet = RawPacket::zeroed_until(CCID_HEADER_LEN);
-        packet[0] = 0x6c;
-        packet[6] = self.seq;
-        packet[7] = 1 << 6;
+        packet[0] = message_type;
+        packet[5] = slot;
+        packet[6] = seq;
+        packet[7] = (1 << 6) | icc_status as u8;
         packet[8] = error as u8;
+        // RDR_to_PC_Parameters carries bProtocolNum even for errors
+        if message_type == 0x82 {
+            packet[9] = self.protocol.number();
+        }
+        self.send_packet_assuming_possible(packet);
+    }
+
+    fn handle_escape(&mut self, command: Escape<MSG>) {
+        let Some(handler) = self.escape_handler else {
+            info!("no escape handler");
+            self.send_error(CcidError::CommandNotSupported);
+            return;
+        };
+        // there is a single response buffer
+        if self.slots.iter().any(|slot| slot.escape_sent.is_some()) {
+            info!("escape response of another slot in progress");
+            self.send_error(CcidError::CmdSlotBusy);
+            return;
+        }
+        // the advertised dwMaxCCIDMessageLength does not exceed MSG
+        let capacity = self.max_message_length - CCID_HEADER_LEN;
+        self.escape_response.clear();
+        self.escape_response.resize_default(capacity).ok();
+        let len = handler(command.data(), &mut self.escape_response);
+        if len > capacity {
+            error!("escape response exceeds dwMaxCCIDMessageLength: {}", len);
+            self.send_error(CcidError::CmdAborted);
+            return;
+        }
+        self.escape_response.truncate(len);
+
+        self.last_response_was_error = false;
+        // the header goes with the first chunk, the rest follows in further packets
+        let chunk_len = len.min(PACKET_SIZE - CCID_HEADER_LEN);
+        let mut packet = RawPacket::zeroed_until(CCID_HEADER_LEN + chunk_len);
+        packet[0] = 0x83;
+        packet[1..5].copy_from_slice(&(len as u32).to_le_bytes());
+        packet[5] = self.current as u8;
+        packet[6] = self.slot().seq;
+        packet[7] = self.slot().icc_status as u8;
+        packet[CCID_HEADER_LEN..].copy_from_slice(&self.escape_response[..chunk_len]);
+        // set before queueing the packet, it must not be terminated with a ZLP
+        self.slot_mut().escape_sent = (chunk_len < len).then_some(chunk_len);
         self.send_packet_assuming_possible(packet);
     }
 
+    // Queues the next packet of the RDR_to_PC_Escape being sent by the slot at `index`, once
+    // the previous one was written.
+    fn prime_escape(&mut self, index: usize) {
+        let slot = &mut self.slots[index];
+        let Some(sent) = slot.escape_sent else {
+            return;
+        };
+        if !slot.outbox.is_empty() {
+            return;
+        }
+        let rest = &self.escape_response[sent..];
+        let chunk_len = rest.len().min(PACKET_SIZE);
+        let packet = RawPacket::from_slice(&rest[..chunk_len]).expect("chunk fits into a packet");
+        let sent = sent + chunk_len;
+        slot.escape_sent = (sent < self.escape_response.len()).then_some(sent);
+        slot.outbox.push_back(packet).ok();
+    }
+
+    /// Whether any slot still has response packets to write, see
+    /// `Ccid::response_in_progress`.
+    pub fn response_in_progress(&self) -> bool {
+        self.slots.iter().any(|slot| {
+            !slot.outbox.is_empty() || matches!(slot.state, State::ReadyToSend | State::Sending)
+        })
+    }
+
+    /// Whether the command being processed is the abData of a Secure message, see
+    /// `Ccid::is_secure_command`.
+    pub fn is_secure_command(&self) -> bool {
+        self.secure
+    }
+
+    // Forwards the abData of a Secure message (bPINOperation followed by the PIN verification
+    // or modification data structure) to the application, answered like an XfrBlock.
+    fn handle_secure(&mut self, command: Secure<MSG>) {
+        // bError carries the offset of the rejected field in the message
+        const LEVEL_PARAMETER: u8 = 8;
+        const PIN_OPERATION: u8 = CCID_HEADER_LEN as u8;
+
+        if self.slot().state == State::ResponsePending {
+            info!("dropping unfetched response");
+            self.discard_response();
+        }
+        if self.interchange_owner().is_some() {
+            info!("interchange busy");
+            self.send_data_block_error(CcidError::CmdSlotBusy as u8);
+            return;
+        }
+        if !matches!(command.chain(), Ok(Chain::BeginsAndEnds)) {
+            info!("Secure: chained PIN blocks are not supported");
+            self.send_data_block_error(LEVEL_PARAMETER);
+            return;
+        }
+        // only the advertised PIN verification (0) and modification (1) reach the application
+        let supported = match command.data().first() {
+            Some(0) => self.pin_support & PIN_SUPPORT_VERIFICATION != 0,
+            Some(1) => self.pin_support & PIN_SUPPORT_MODIFICATION != 0,
+            _ => false,
+        };
+        if !supported {
+            info!(
+                "Secure: unsupported PIN operation {:?}",
+                command.data().first()
+            );
+            self.send_data_block_error(PIN_OPERATION);
+            return;
+        }
+
+        self.reset_interchange();
+        let Ok(message) = self.interchange.request_mut() else {
+            self.reject_busy_interchange();
+            return;
+        };
+        message.clear();
+        if message.extend_from_slice(command.data()).is_err() {
+            error!("Interchange is full");
+            self.send_wrong_length();
+            return;
+        }
+        self.call_app();
+        self.secure = true;
+        self.set_command_bwi(command.bwi());
+        self.slot_mut().state = State::Processing;
+    }
+
+    // There is no clock to change, so the fixed values of the descriptor are reported.
+    fn set_data_rate_and_clock_frequency(&mut self, command: SetDataRateAndClockFrequency<MSG>) {
+        let data = command.data();
+        if data.get(..4) != Some(&CLOCK_FREQUENCY_KHZ[..])
+            || data.get(4..8) != Some(&DATA_RATE_BPS[..])
+        {
+            info!(
+                "ignoring requested clock frequency and data rate {:?}",
+                data
+            );
+        }
+
+        self.last_response_was_error = false;
+        let mut packet = RawPacket::zeroed_until(CCID_HEADER_LEN + 8);
+        packet[0] = 0x84;
+        packet[1] = 8;
+        packet[5] = self.current as u8;
+        packet[6] = self.slot().seq;
+        packet[7] = self.slot().icc_status as u8;
+        packet[CCID_HEADER_LEN..][..4].copy_from_slice(&CLOCK_FREQUENCY_KHZ);
+        packet[CCID_HEADER_LEN + 4..][..4].copy_from_slice(&DATA_RATE_BPS);
+        self.send_packet_assuming_possible(packet);
+    }
+
+    fn set_parameters(&mut self, command: SetParameters<MSG>) {
+        // bError carries the offset of the first rejected field in the message.  The ATR
+        // announced the protocol, so switching it mid-session is rejected.
+        if command.protocol() != self.protocol.number() {
+            info!("SetParameters: unsupported protocol {}", command.protocol());
+            self.send_parameters_error(7);
+            return;
+        }
+        let data = command.protocol_data();
+        let parameters = match Vec::from_slice(data) {
+            Ok(parameters) if data.len() == self.protocol.default_parameters().len() => parameters,
+            _ => {
+                info!("SetParameters: unexpected length {}", data.len());
+                self.send_parameters_error(CCID_HEADER_LEN as u8);
+                return;
+            }
+        };
+        if let Some(offset) = self.unsupported_parameter(&parameters) {
+            info!("SetParameters: unsupported value at offset {}", offset);
+            self.send_parameters_error(offset);
+            return;
+        }
+        self.slot_mut().parameters = parameters;
+        self.send_parameters();
+    }
+
+    // Offset (in the message) of the first abProtocolDataStructure field we cannot honour.
+    fn unsupported_parameter(&self, parameters: &[u8]) -> Option<u8> {
+        const FINDEX_DINDEX: u8 = CCID_HEADER_LEN as u8;
+        const TCCKS: u8 = FINDEX_DINDEX + 1;
+
+        // bmFindexDindex: the RFU entries of the Fi and Di tables
+        let (fi, di) = (parameters[0] >> 4, parameters[0] & 0x0f);
+        if matches!(fi, 7 | 8 | 14 | 15) || matches!(di, 0 | 10..=15) {
+            return Some(FINDEX_DINDEX);
+        }
+        // bmTCCKST0/bmTCCKST1: only the direct convention, LRC or CRC for T=1
+        let supported = match self.protocol {
+            Protocol::T0 => parameters[1] == 0x00,
+            Protocol::T1 => parameters[1] & !0x01 == 0x10,
+        };
+        if !supported {
+            return Some(TCCKS);
+        }
+        None
+    }
+
     fn send_parameters(&mut self) {
-        let mut packet = RawPacket::zeroed_until(17);
+        self.last_response_was_error = false;
+        let parameters = self.slot().parameters.clone();
+        let mut packet = RawPacket::zeroed_until(CCID_HEADER_LEN + parameters.len());
         packet[0] = 0x82;
-        packet[1] = 7;
-        packet[6] = self.seq;
-        packet[9] = 1; // T=1
-
-        // just picking the fastest values.
-        //              Fi = 1Mz    Di=1
-        packet[10] = (0b0001 << 4) | (0b0001);
-
-        // just taking default value from spec.
-        packet[11] = 0x10;
-        // not sure, taking default.
-        packet[13] = 0x15;
-        // set max waiting time
-        packet[15] = 0xfe;
+        packet[1] = parameters.len() as u8;
+        packet[5] = self.current as u8;
+        packet[6] = self.slot().seq;
+        packet[7] = self.slot().icc_status as u8;
+        packet[9] = self.protocol.number();
+        packet[CCID_HEADER_LEN..].copy_from_slice(&parameters);
+        self.send_packet_assuming_possible(packet);
+    }
+
+    fn send_parameters_error(&mut self, offset: u8) {
+        self.last_response_was_error = true;
+        let mut packet = RawPacket::zeroed_until(CCID_HEADER_LEN);
+        packet[0] = 0x82;
+        packet[5] = self.current as u8;
+        packet[6] = self.slot().seq;
+        packet[7] = (1 << 6) | self.slot().icc_status as u8;
+        packet[8] = offset;
+        packet[9] = self.protocol.number();
         self.send_packet_assuming_possible(packet);
     }
 
     fn send_atr(&mut self) {
-        let atr = self.atr.clone();
-        let packet = DataBlock::new(
-            self.seq,
+        self.last_response_was_error = false;
+        let slot = self.slot();
+        // the packet is built in place, ending the borrow of the ATR before it is queued
+        let packet: RawPacket = DataBlock::new(
+            self.current as u8,
+            slot.seq,
+            slot.icc_status,
             Chain::BeginsAndEnds,
-            &atr,
+            &slot.atr,
             // T=0, T=1, command chaining/extended Lc+Le/no logical channels, card issuer's data "Solo 2"
             // 3B 8C 80 01 80 73 C0 21 C0 56 53 6F 6C 6F 20 32 A4
             // https://smartcard-atr.apdu.fr/parse?ATR=3B+8C+80+01+80+73+C0+21+C0+56+53+6F+6C+6F+20+32+A4
@@ -545,28 +2017,75 @@ where
             // https://smartcard-atr.apdu.fr/parse?ATR=3B+F8+13+00+00+81+31+FE+15+59+75+62+69+6B+65+79+34+D4
             // At least TB(1) is deprecated, so it makes no sense
             // Also, there TD(1) = 0x81 and TD(2) = 0x31 both refer to protocol T=1 which seems wrong
-        );
-        self.send_packet_assuming_possible(packet.into());
+        )
+        .into();
+        self.send_packet_assuming_possible(packet);
     }
 
     fn send_packet_assuming_possible(&mut self, packet: RawPacket) {
-        if self.outbox.is_some() {
-            // Previous transaction will fail, but we'll be ready for new transactions.
-            self.state = State::Idle;
-            info!("overwriting last session..");
+        if self.slot().outbox.is_full() {
+            // make room, if the endpoint takes the oldest packet
+            self.maybe_send_packet().ok();
+        }
+        if self.slot_mut().outbox.push_back(packet).is_err() {
+            // the queued packets go out unharmed, the new one is lost
+            error!("outbox full, dropping packet");
+            self.stats.dropped_packets = self.stats.dropped_packets.wrapping_add(1);
+            return;
         }
-        self.outbox = Some(packet);
 
         // fast-lane response attempt
-        self.maybe_send_packet();
+        self.maybe_send_packet().ok();
+    }
+
+    /// Advance the state machine: pick up the response of the application, retry blocked
+    /// packets and send pending notifications.
+    ///
+    /// This is what the class `poll()` does; the granular methods remain for firmware that
+    /// needs to drive the steps itself.
+    pub fn poll(&mut self) -> PollResult {
+        let result = self.poll_app();
+        // a failed write is recorded for `take_write_error`
+        self.flush_outbox().ok();
+        self.maybe_send_notification();
+        result
     }
 
+    /// Retry writing a packet that was blocked by the endpoint, returns whether all packets
+    /// have been written, or the error of the endpoint (after dropping the transfer).
+    ///
+    /// A blocked packet is otherwise only retried once the previous IN transfer completed, so
+    /// this should be called from the class `poll()`.
+    pub fn flush_outbox(&mut self) -> Result<bool, UsbError> {
+        self.maybe_send_packet()
+    }
+
+    /// The last error returned by the bulk IN endpoint, if it has not been taken yet.
+    pub fn take_write_error(&mut self) -> Option<UsbError> {
+        self.last_write_error.take()
+    }
+
+    /// Write the next queued packet, returns whether all packets have been written.
+    ///
+    /// Errors of the endpoint other than `WouldBlock` drop the transfer and are returned.
     #[inline(never)]
-    pub fn maybe_send_packet(&mut self) {
-        if let Some(packet) = self.outbox.as_ref() {
-            let needs_zlp = packet.len() == PACKET_SIZE;
+    pub fn maybe_send_packet(&mut self) -> Result<bool, UsbError> {
+        // the slots share the bulk IN endpoint, lower slots go first
+        let Some(index) = self.slots.iter().position(|slot| !slot.outbox.is_empty()) else {
+            return Ok(true);
+        };
+        let slot = &mut self.slots[index];
+        if let Some(packet) = slot.outbox.front() {
+            // a transfer ending with a full packet must be terminated with a ZLP, a message
+            // spanning several packets continues instead
+            let needs_zlp =
+                packet.len() == self.write.max_packet_size() as usize && slot.escape_sent.is_none();
             match self.write.write(packet) {
                 Ok(n) if n == packet.len() => {
+                    if let Some(tap) = self.packet_tap.as_mut() {
+                        tap(UsbDirection::In, packet);
+                    }
+                    self.stats.bytes_sent = self.stats.bytes_sent.wrapping_add(packet.len() as u32);
                     // if packet.len() > 8 {
                     //     info!("--> sent {:?}... successfully", &packet[..8]).ok();
                     // } else {
@@ -574,28 +2093,37 @@ where
                     // }
 
                     if needs_zlp {
-                        self.outbox = Some(RawPacket::new());
+                        // the ZLP has to go out before the next queued packet
+                        if let Some(packet) = slot.outbox.front_mut() {
+                            packet.clear();
+                        }
                     } else {
-                        self.outbox = None;
+                        slot.outbox.pop_front();
+                        self.prime_escape(index);
                     }
                 }
                 Ok(_sent) => {
                     error!("Failed to send entire packet, sent only {}", _sent);
-                    self.reset_state()
+                    self.reset_after(ResetReason::WriteFailed);
+                    // the endpoint buffer is smaller than the packet
+                    self.last_write_error = Some(UsbError::BufferOverflow);
+                    return Err(UsbError::BufferOverflow);
                 }
 
                 Err(UsbError::WouldBlock) => {
-                    // fine, can't write try later
-                    // this shouldn't happen probably
+                    // fine, can't write try later, see `flush_outbox`
                     info!("waiting to send");
                 }
 
-                Err(_err) => {
-                    error!("Failed to send packet {:?}", _err);
-                    self.reset_state()
+                Err(err) => {
+                    error!("Failed to send packet {:?}", err);
+                    self.reset_after(ResetReason::WriteFailed);
+                    self.last_write_error = Some(copy_usb_error(&err));
+                    return Err(err);
                 }
             }
         }
+        Ok(self.slots.iter().all(|slot| slot.outbox.is_empty()))
     }
 
     // pub fn read_address(&self) -> EndpointAddress {
@@ -608,32 +2136,53 @@ where
 
     // Called if we receive an ABORT request on the control pipe.
     pub fn expect_abort(&mut self, slot: u8, seq: u8) {
-        debug_assert!(slot == 0);
-        info!("ABORT expected for seq = {}", seq);
-        // We only have one slot (see FUNCTIONAL_INTERFACE_DESCRIPTOR in constants.rs)
-        if slot != 0 {
+        info!("ABORT expected for slot = {}, seq = {}", slot, seq);
+        if slot >= self.num_slots {
             return;
         }
-        if self.bulk_abort == Some(seq) {
+        self.current = slot as usize;
+        if self.slot().bulk_abort == Some(seq) {
             self.abort();
         } else {
-            self.control_abort = Some(seq);
+            self.slot_mut().control_abort = Some(seq);
         }
     }
 
     // This method performs an abort and should only be called if we received matching ABORT
     // requets both from the control pipe and from the bulk endpoint.
     fn abort(&mut self) {
-        // reset state
-        self.bulk_abort = None;
-        self.control_abort = None;
-        self.state = State::Idle;
-        self.outbox = None;
-        self.started_processing = false;
-        self.receiving_long = false;
-        self.long_packet_missing = 0;
+        self.slot_mut().abort();
+        self.abort_transfer();
+        // drop whatever the interchange holds for this slot, unless another slot is using it
+        if self.interchange_owner().is_none() {
+            self.reset_interchange();
+        }
 
         // send response for successful abort
+        self.stats.aborts = self.stats.aborts.wrapping_add(1);
         self.send_slot_status_ok();
     }
+
+    /// Tear down the transfers of all slots like an acknowledged abort, without sending a response.
+    ///
+    /// Use this when the host is gone (e.g. firmware-initiated reset during USB teardown) and
+    /// writing a slot status to the endpoint would be pointless.  When the host requested the
+    /// abort, it expects the acknowledgement, which the pipe sends by itself.
+    pub fn abort_silent(&mut self) {
+        for slot in &mut self.slots {
+            slot.abort();
+        }
+        self.abort_transfer();
+        self.reset_interchange();
+    }
+
+    // Resets the transfer state shared by all slots.
+    fn abort_transfer(&mut self) {
+        self.started_processing = false;
+        self.receiving_long = false;
+        self.discarding_long = false;
+        self.long_packet_missing = 0;
+        self.t0_more = false;
+        self.reset_stream();
+    }
 }
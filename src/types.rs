@@ -1,7 +1,14 @@
 use embedded_time::duration::Milliseconds;
 
+use crate::constants::{MAX_IFSD, PROTOCOL_T0, PROTOCOL_T1, T0_PARAMETERS, T1_PARAMETERS};
+
 // pub mod apdu;
-pub(crate) mod packet;
+mod atr;
+mod descriptor;
+pub mod packet;
+
+pub use atr::{AtrBuilder, AtrError};
+pub use descriptor::CcidDescriptor;
 
 // pub type MessageBuffer = apdu_dispatch::interchanges::Data;
 
@@ -17,6 +24,249 @@ pub enum Status {
     ReceivedData(Milliseconds),
 }
 
+/// Errors reported to the host in the bError field of a failed response.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+#[allow(clippy::enum_variant_names)]
+pub enum CcidError {
+    CmdAborted = 0xff,
+    IccMute = 0xfe,
+    XfrParityError = 0xfd,
+    //..
+    CmdSlotBusy = 0xE0,
+    /// The bSlot of the command does not address an existing slot.
+    BadSlot = 0x05,
+    /// The bFunction of a Mechanical command is not defined.
+    BadFunction = 0x07,
+    CommandNotSupported = 0x00,
+}
+
+impl core::convert::TryFrom<u8> for CcidError {
+    type Error = ();
+    fn try_from(error: u8) -> core::result::Result<Self, ()> {
+        Ok(match error {
+            0xff => Self::CmdAborted,
+            0xfe => Self::IccMute,
+            0xfd => Self::XfrParityError,
+            0xe0 => Self::CmdSlotBusy,
+            0x05 => Self::BadSlot,
+            0x07 => Self::BadFunction,
+            0x00 => Self::CommandNotSupported,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Invalid configuration of the CCID class.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The dwFeatures do not select APDU level exchange, which is the only one implemented,
+    /// or select extended APDUs with an interchange too small to hold one.
+    UnsupportedFeatures,
+    /// The number of slots is zero or exceeds the supported maximum.
+    UnsupportedSlotCount,
+    /// The slot index does not address a configured slot.
+    InvalidSlot,
+    /// The card issuer's data exceeds 13 bytes, and does not fit into the ATR.
+    IssuerDataTooLong,
+    /// The ATR exceeds `MAX_ATR_LEN` bytes.
+    AtrTooLong,
+    /// The slot has a transfer in progress.
+    SlotBusy,
+    /// The dwMaxCCIDMessageLength is smaller than a packet or exceeds the message buffer.
+    UnsupportedMessageLength,
+    /// The bPINSupport sets bits other than verification (0x01) and modification (0x02).
+    UnsupportedPinSupport,
+    /// The class was built without an interrupt endpoint, see `CcidBuilder::interrupt`.
+    NoInterruptEndpoint,
+}
+
+/// A change of the card in a slot, reported with `Ccid::on_card_event`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CardEvent {
+    /// An ICC was inserted, notified with RDR_to_PC_NotifySlotChange.
+    Inserted,
+    /// The ICC was removed, notified with RDR_to_PC_NotifySlotChange.
+    Removed,
+    /// The slot failed, e.g. with an overcurrent (0x01), notified with RDR_to_PC_HardwareError
+    /// carrying the bHardwareErrorCode.
+    HardwareError(u8),
+}
+
+/// bmICCStatus, reported in the bStatus field of every response.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IccStatus {
+    /// An ICC is present and active (powered on).
+    #[default]
+    Active = 0,
+    /// An ICC is present and inactive (powered off).
+    Inactive = 1,
+    /// No ICC is present.
+    NotPresent = 2,
+}
+
+/// Transmission protocol announced in the ATR and the functional descriptor.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// T=0, for legacy middleware.
+    ///
+    /// Response APDUs with more than 256 bytes of data are split: the host gets the first
+    /// 256 bytes with SW 61XX and fetches the remainder with GET RESPONSE.
+    T0,
+    /// T=1
+    #[default]
+    T1,
+}
+
+impl Protocol {
+    /// bProtocolNum
+    pub(crate) fn number(self) -> u8 {
+        match self {
+            Self::T0 => PROTOCOL_T0,
+            Self::T1 => PROTOCOL_T1,
+        }
+    }
+
+    /// The default abProtocolDataStructure, as reported by GetParameters.
+    pub(crate) fn default_parameters(self) -> &'static [u8] {
+        match self {
+            Self::T0 => &T0_PARAMETERS,
+            Self::T1 => &T1_PARAMETERS,
+        }
+    }
+}
+
+/// The T=1 abProtocolDataStructure reported by GetParameters, see
+/// `CcidBuilder::t1_parameters`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct T1Parameters {
+    /// bmFindexDindex: Fi in the high nibble, Di in the low nibble
+    pub fi_di: u8,
+    /// bmTCCKST1: checksum type (bit 0: CRC instead of LRC) and convention (bit 1: inverse)
+    pub tcckst: u8,
+    /// bGuardTimeT1: extra guard time
+    pub guard_time: u8,
+    /// bmWaitingIntegersT1: BWI in the high nibble, CWI in the low nibble
+    pub wi: u8,
+    /// bClockStop
+    pub clock_stop: u8,
+    /// bIFSC: the size of the information field
+    pub ifsc: u8,
+    /// bNadValue
+    pub nad: u8,
+}
+
+impl Default for T1Parameters {
+    fn default() -> Self {
+        T1_PARAMETERS.into()
+    }
+}
+
+impl From<[u8; 7]> for T1Parameters {
+    fn from(bytes: [u8; 7]) -> Self {
+        let [fi_di, tcckst, guard_time, wi, clock_stop, ifsc, nad] = bytes;
+        Self {
+            fi_di,
+            tcckst,
+            guard_time,
+            wi,
+            clock_stop,
+            ifsc,
+            nad,
+        }
+    }
+}
+
+impl T1Parameters {
+    /// The abProtocolDataStructure, in message order.
+    pub fn to_bytes(&self) -> [u8; 7] {
+        [
+            self.fi_di,
+            self.tcckst,
+            self.guard_time,
+            self.wi,
+            self.clock_stop,
+            self.ifsc,
+            self.nad,
+        ]
+    }
+}
+
+/// The protocol parameters of a slot, as reported by GetParameters, see `Ccid::parameters`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Parameters {
+    /// The T=0 abProtocolDataStructure, in message order.
+    T0([u8; 5]),
+    /// The T=1 abProtocolDataStructure.
+    T1(T1Parameters),
+}
+
+impl Parameters {
+    /// The IFSD, the largest information field the host accepts, for T=1.
+    ///
+    /// With APDU level exchange the host does not run T=1 framing, so it never negotiates
+    /// the IFSD at the CCID layer: SetParameters only carries the card's bIFSC.  The value
+    /// is always the dwMaxIFSD of the functional descriptor (254).
+    pub fn ifsd(&self) -> Option<u8> {
+        match self {
+            Self::T0(_) => None,
+            Self::T1(_) => Some(MAX_IFSD[0]),
+        }
+    }
+}
+
+/// Why the pipe dropped the transfer in progress, see `Ccid::take_last_reset_reason`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResetReason {
+    /// A packet was too short to hold a CCID header.
+    ShortPacket,
+    /// A message did not fit into the receive buffer.
+    MessageTooLong,
+    /// A single packet message did not match its dwLength, or a longer one did not start
+    /// with a full packet.
+    LengthMismatch,
+    /// A command carried an unexpected sequence number, see `SeqPolicy::Strict`.
+    UnexpectedSeq,
+    /// An XfrBlock carried an unknown wLevelParameter.
+    UnknownChain,
+    /// A command or chain parameter did not fit the state of the transfer.
+    UnexpectedCommand,
+    /// A control request had an unexpected direction.
+    UnexpectedRequest,
+    /// No room was left to queue the next response packet.
+    OutboxFull,
+    /// The response to send vanished from the interchange.
+    MissingResponse,
+    /// Writing to the bulk IN endpoint failed.
+    WriteFailed,
+}
+
+/// Validation of the bSeq field of incoming commands.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SeqPolicy {
+    /// Accept any sequence number and echo it back.
+    #[default]
+    Tolerant,
+    /// Expect every command to carry the sequence number of the previous one plus one
+    /// (wrapping at 255), and reset the pipe otherwise.  A block of a chained transfer out of
+    /// sequence fails with `CmdAborted` instead, dropping the transfer.
+    ///
+    /// This helps diagnosing hosts that mismanage the sequence numbers.
+    Strict,
+}
+
 impl core::convert::TryFrom<u8> for ClassRequest {
     type Error = ();
     fn try_from(request: u8) -> core::result::Result<Self, ()> {
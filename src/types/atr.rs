@@ -0,0 +1,239 @@
+use heapless::Vec;
+
+use crate::constants::MAX_ATR_LEN;
+
+use super::Protocol;
+
+// Fi = 372, Di = 1, assumed if TA(1) is absent
+const DEFAULT_FI_DI: u8 = 0x11;
+// Fi = 512, Di = 32
+const FAST_FI_DI: u8 = 0x96;
+
+/// The ATR could not be built.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AtrError {
+    /// More than 15 historical bytes, their number has to fit into the low nibble of T0.
+    HistoricalBytesTooLong,
+    /// The ATR exceeds `MAX_ATR_LEN` bytes.
+    TooLong,
+    /// The card capabilities do not have between one and three bytes.
+    InvalidCardCapabilities,
+}
+
+/// Builder for an Answer-to-Reset announcing a single protocol.
+///
+/// The ATR consists of TS, T0, optionally TA1, TD1 (announcing the protocol), the historical
+/// bytes and, unless the protocol is T=0, the TCK checksum.  For instance, the card issuer's data "Solo 2" with
+/// T=1 gives `3B 88 01 80 56 53 6F 6C 6F 20 32 72`.
+#[derive(Copy, Clone, Debug)]
+pub struct AtrBuilder<'a> {
+    inverse_convention: bool,
+    protocol: Protocol,
+    fast_timing: bool,
+    historical_bytes: &'a [u8],
+    card_service_data: Option<u8>,
+    card_capabilities: Option<&'a [u8]>,
+    card_issuers_data: Option<&'a [u8]>,
+    status_indicator: Option<[u8; 3]>,
+}
+
+impl Default for AtrBuilder<'_> {
+    fn default() -> Self {
+        Self {
+            inverse_convention: false,
+            protocol: Protocol::T1,
+            fast_timing: false,
+            historical_bytes: &[],
+            card_service_data: None,
+            card_capabilities: None,
+            card_issuers_data: None,
+            status_indicator: None,
+        }
+    }
+}
+
+impl<'a> AtrBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// TS = 3B (default).
+    pub fn direct_convention(mut self) -> Self {
+        self.inverse_convention = false;
+        self
+    }
+
+    /// TS = 3F.
+    pub fn inverse_convention(mut self) -> Self {
+        self.inverse_convention = true;
+        self
+    }
+
+    /// Announce T=0, which omits TCK.
+    pub fn protocol_t0(mut self) -> Self {
+        self.protocol = Protocol::T0;
+        self
+    }
+
+    /// Announce T=1 (default).
+    pub fn protocol_t1(mut self) -> Self {
+        self.protocol = Protocol::T1;
+        self
+    }
+
+    /// Announce Fi = 512 and Di = 32 in TA1, for a byte exchange about 23 times faster than
+    /// with the default Fi = 372 and Di = 1.
+    ///
+    /// Not all readers support this rate.  The protocol parameters have to report the same
+    /// bmFindexDindex, see [`fi_di`](Self::fi_di) and `CcidBuilder::fast_timing`.
+    pub fn fast_timing(mut self) -> Self {
+        self.fast_timing = true;
+        self
+    }
+
+    /// The Fi/Di announced in TA1 (Fi in the high nibble, Di in the low nibble), or the
+    /// default 0x11 if TA1 is absent.
+    pub fn fi_di(&self) -> u8 {
+        if self.fast_timing {
+            FAST_FI_DI
+        } else {
+            DEFAULT_FI_DI
+        }
+    }
+
+    /// Raw historical bytes, e.g. those of a real card being emulated.
+    ///
+    /// Replaces the COMPACT-TLV objects, such as the card issuer's data.
+    pub fn historical_bytes(mut self, historical_bytes: &'a [u8]) -> Self {
+        self.historical_bytes = historical_bytes;
+        self.card_service_data = None;
+        self.card_capabilities = None;
+        self.card_issuers_data = None;
+        self
+    }
+
+    /// Card service data, sent with the tag 31 before the card capabilities, e.g. 0xF8 for a
+    /// card supporting application selection by full and partial DF name.
+    ///
+    /// Replaces the raw historical bytes.
+    pub fn card_service_data(mut self, card_service_data: u8) -> Self {
+        self.card_service_data = Some(card_service_data);
+        self.historical_bytes = &[];
+        self
+    }
+
+    /// Card capabilities of one to three bytes, sent with the tag 7X before the card issuer's
+    /// data.  The third byte announces command chaining (0x80) and extended Lc and Le fields
+    /// (0x40), which host middleware checks before sending long APDUs.
+    ///
+    /// Replaces the raw historical bytes.
+    pub fn card_capabilities(mut self, card_capabilities: &'a [u8]) -> Self {
+        self.card_capabilities = Some(card_capabilities);
+        self.historical_bytes = &[];
+        self
+    }
+
+    /// Card issuer's data of at most 13 bytes (9 with a status indicator, less with other
+    /// objects), sent as historical bytes after the category indicator 80 and the tag 5X.
+    ///
+    /// Replaces the raw historical bytes.
+    pub fn card_issuers_data(mut self, card_issuers_data: &'a [u8]) -> Self {
+        self.card_issuers_data = Some(card_issuers_data);
+        self.historical_bytes = &[];
+        self
+    }
+
+    /// Status indicator (life cycle status, SW1 and SW2), e.g. `[0x00, 0x90, 0x00]`, sent with
+    /// the tag 83 after the card issuer's data.
+    ///
+    /// Ignored with raw historical bytes, which have to include the status indicator
+    /// themselves, or without any other COMPACT-TLV object.
+    pub fn status_indicator(mut self, status_indicator: [u8; 3]) -> Self {
+        self.status_indicator = Some(status_indicator);
+        self
+    }
+
+    pub fn build(&self) -> Result<Vec<u8, MAX_ATR_LEN>, AtrError> {
+        if self
+            .card_capabilities
+            .is_some_and(|capabilities| !(1..=3).contains(&capabilities.len()))
+        {
+            return Err(AtrError::InvalidCardCapabilities);
+        }
+        let compact_tlv = self.card_service_data.is_some()
+            || self.card_capabilities.is_some()
+            || self.card_issuers_data.is_some();
+        let status_indicator = self.status_indicator.filter(|_| compact_tlv);
+        let k = if compact_tlv {
+            // each object has a tag byte, the category indicator comes first
+            1 + self.card_service_data.map_or(0, |_| 2)
+                + self.card_capabilities.map_or(0, |data| 1 + data.len())
+                + self.card_issuers_data.map_or(0, |data| 1 + data.len())
+                + status_indicator.map_or(0, |status| 1 + status.len())
+        } else {
+            self.historical_bytes.len()
+        };
+        // TS, T0, TA1, TD1 and TCK always fit next to 15 historical bytes
+        if k > 0x0f {
+            return Err(AtrError::HistoricalBytesTooLong);
+        }
+
+        // the length check above keeps the ATR within MAX_ATR_LEN, but never emit a truncated one
+        let mut atr = Vec::new();
+        // TS
+        let ts = if self.inverse_convention { 0x3F } else { 0x3B };
+        // T0: TD1 (and TA1) follow, encode length of historical bytes
+        let ta1 = if self.fast_timing { 0x10 } else { 0 };
+        atr.extend_from_slice(&[ts, 0x80 | ta1 | k as u8])
+            .map_err(|_| AtrError::TooLong)?;
+        if self.fast_timing {
+            atr.push(self.fi_di()).map_err(|_| AtrError::TooLong)?;
+        }
+        // TD1: the protocol, no more interface bytes
+        atr.push(self.protocol.number())
+            .map_err(|_| AtrError::TooLong)?;
+
+        if compact_tlv {
+            // category indicator: compact-TLV
+            atr.push(0x80).map_err(|_| AtrError::TooLong)?;
+            if let Some(data) = self.card_service_data {
+                // tag 3: card service data
+                atr.extend_from_slice(&[0x31, data])
+                    .map_err(|_| AtrError::TooLong)?;
+            }
+            if let Some(data) = self.card_capabilities {
+                // tag 7: card capabilities
+                atr.push(0x70 | data.len() as u8)
+                    .map_err(|_| AtrError::TooLong)?;
+                atr.extend_from_slice(data).map_err(|_| AtrError::TooLong)?;
+            }
+            if let Some(data) = self.card_issuers_data {
+                // tag 5: card issuer's data
+                atr.push(0x50 | data.len() as u8)
+                    .map_err(|_| AtrError::TooLong)?;
+                atr.extend_from_slice(data).map_err(|_| AtrError::TooLong)?;
+            }
+            if let Some(status) = status_indicator {
+                // tag 8: status indicator
+                atr.push(0x83).map_err(|_| AtrError::TooLong)?;
+                atr.extend_from_slice(&status)
+                    .map_err(|_| AtrError::TooLong)?;
+            }
+        } else {
+            atr.extend_from_slice(self.historical_bytes)
+                .map_err(|_| AtrError::TooLong)?;
+        }
+        // TCK is absent if only T=0 is indicated
+        if self.protocol != Protocol::T0 {
+            // xor of all bytes except TS
+            let mut checksum = 0;
+            for byte in atr.iter().skip(1) {
+                checksum ^= *byte;
+            }
+            atr.push(checksum).map_err(|_| AtrError::TooLong)?;
+        }
+
+        Ok(atr)
+    }
+}
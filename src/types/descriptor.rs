@@ -0,0 +1,49 @@
+use crate::constants::*;
+
+/// The fields of the CCID functional descriptor that depend on the configuration.
+///
+/// Obtained by parsing the bytes returned by [`Ccid::functional_descriptor`], or those a host
+/// read from the configuration descriptor.
+///
+/// [`Ccid::functional_descriptor`]: crate::Ccid::functional_descriptor
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CcidDescriptor {
+    pub bcd_ccid: u16,
+    pub max_slot_index: u8,
+    pub protocols: u32,
+    pub features: u32,
+    pub max_message_length: u32,
+    pub pin_support: u8,
+    pub max_busy_slots: u8,
+}
+
+impl CcidDescriptor {
+    /// Parse the payload of a functional descriptor, without the bLength and bDescriptorType
+    /// bytes.  Returns `None` if it is shorter than [`FUNCTIONAL_INTERFACE_DESCRIPTOR_LEN`].
+    pub fn parse(descriptor: &[u8]) -> Option<Self> {
+        if descriptor.len() < FUNCTIONAL_INTERFACE_DESCRIPTOR_LEN {
+            return None;
+        }
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                descriptor[offset],
+                descriptor[offset + 1],
+                descriptor[offset + 2],
+                descriptor[offset + 3],
+            ])
+        };
+        Some(Self {
+            bcd_ccid: u16::from_le_bytes([
+                descriptor[BCD_CCID_OFFSET],
+                descriptor[BCD_CCID_OFFSET + 1],
+            ]),
+            max_slot_index: descriptor[MAX_SLOT_INDEX_OFFSET],
+            protocols: u32_at(PROTOCOLS_OFFSET),
+            features: u32_at(FEATURES_OFFSET),
+            max_message_length: u32_at(MAX_MSG_LENGTH_OFFSET),
+            pin_support: descriptor[PIN_SUPPORT_OFFSET],
+            max_busy_slots: descriptor[MAX_BUSY_SLOTS_OFFSET],
+        })
+    }
+}
@@ -2,25 +2,21 @@ use core::convert::{TryFrom, TryInto};
 
 use crate::constants::*;
 
+use super::IccStatus;
+
 pub type RawPacket = heapless::Vec<u8, PACKET_SIZE>;
-pub type ExtPacket = heapless::Vec<u8, MAX_MSG_LENGTH>;
+pub type ExtPacket<const M: usize = MAX_MSG_LENGTH> = heapless::Vec<u8, M>;
 
 pub trait RawPacketExt {
-    fn data_len(&self) -> usize;
-    fn zeroed() -> Self;
+    /// dwLength, or `None` if the packet is too short to contain it.
+    fn data_len(&self) -> Option<usize>;
     fn zeroed_until(len: usize) -> Self;
 }
 
 impl RawPacketExt for RawPacket {
-    fn data_len(&self) -> usize {
-        u32::from_le_bytes(self[1..5].try_into().unwrap()) as usize
-    }
-
-    fn zeroed() -> Self {
-        let mut res = Self::new();
-        let cap = res.capacity();
-        res.resize_default(cap).unwrap();
-        res
+    fn data_len(&self) -> Option<usize> {
+        let len = self.get(1..5)?.try_into().ok()?;
+        Some(u32::from_le_bytes(len) as usize)
     }
 
     fn zeroed_until(len: usize) -> Self {
@@ -31,16 +27,19 @@ impl RawPacketExt for RawPacket {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
     ShortPacket,
     UnknownCommand(u8),
+    /// The message does not fit into the buffer, see [`parse_command`].
+    MessageTooLong,
 }
 
-pub trait Packet: core::ops::Deref<Target = ExtPacket> {
+pub trait Packet<const M: usize>: core::ops::Deref<Target = ExtPacket<M>> {
+    /// bSlot, which the pipe checks against the configured number of slots.
     #[inline]
     fn slot(&self) -> u8 {
-        // we have only one slot
-        assert!(self[5] == 0);
         self[5]
     }
 
@@ -50,21 +49,23 @@ pub trait Packet: core::ops::Deref<Target = ExtPacket> {
     }
 }
 
-pub trait PacketWithData: Packet {
+pub trait PacketWithData<const M: usize>: Packet<M> {
+    /// abData, as declared by dwLength and limited to the received bytes
     #[inline]
     fn data(&self) -> &[u8] {
         // let len = u32::from_le_bytes(self[1..5].try_into().unwrap()) as usize;
         let declared_len = u32::from_le_bytes(self[1..5].try_into().unwrap()) as usize;
-        let len = core::cmp::min(MAX_MSG_LENGTH - CCID_HEADER_LEN, declared_len);
+        let data = &self[CCID_HEADER_LEN..];
         // hprintln!("delcared = {}, len = {}", declared_len, len).ok();
-        &self[CCID_HEADER_LEN..][..len]
+        &data[..declared_len.min(data.len())]
     }
 }
 
 #[derive(Debug, Default, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct UnknownChaining;
 
-pub trait ChainedPacket: Packet {
+pub trait ChainedPacket<const M: usize>: Packet<M> {
     #[inline(always)]
     fn chain(&self) -> Result<Chain, UnknownChaining> {
         let level_parameter = u16::from_le_bytes(self[8..10].try_into().unwrap());
@@ -77,28 +78,105 @@ pub trait ChainedPacket: Packet {
             _ => Err(UnknownChaining),
         }
     }
+
+    /// bBWI: the block waiting time multiplier granted for this block
+    #[inline]
+    fn bwi(&self) -> u8 {
+        self[7]
+    }
+
+    /// Whether the host pulls the next part of a response, rather than sending a command.
+    #[inline]
+    fn is_response_pull(&self) -> bool {
+        matches!(self.chain(), Ok(Chain::ExpectingMore))
+    }
 }
 
-impl ChainedPacket for XfrBlock {}
+impl<const M: usize> ChainedPacket<M> for XfrBlock<M> {}
+impl<const M: usize> ChainedPacket<M> for Secure<M> {}
 
 pub struct DataBlock<'a> {
+    slot: u8,
     seq: u8,
+    icc_status: IccStatus,
     chain: Chain,
     data: &'a [u8],
 }
 
 impl<'a> DataBlock<'a> {
-    pub fn new(seq: u8, chain: Chain, data: &'a [u8]) -> Self {
+    pub fn new(slot: u8, seq: u8, icc_status: IccStatus, chain: Chain, data: &'a [u8]) -> Self {
         assert!(data.len() + CCID_HEADER_LEN <= PACKET_SIZE);
-        Self { seq, chain, data }
+        Self {
+            slot,
+            seq,
+            icc_status,
+            chain,
+            data,
+        }
+    }
+}
+
+impl<'a> DataBlock<'a> {
+    /// bSlot
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    /// bSeq
+    pub fn seq(&self) -> u8 {
+        self.seq
+    }
+
+    /// The bmICCStatus part of bStatus
+    pub fn icc_status(&self) -> IccStatus {
+        self.icc_status
+    }
+
+    /// bChainParameter
+    pub fn chain(&self) -> Chain {
+        self.chain
+    }
+
+    /// abData
+    pub fn data(&self) -> &'a [u8] {
+        self.data
     }
 }
 
+/// The DataBlocks answering a command with the response `data`, as sent by the pipe.
+///
+/// Each block carries up to `PACKET_SIZE - CCID_HEADER_LEN` bytes.  Blocks after the first
+/// answer the XfrBlocks with which the host pulls them, whose bSeq has to be consecutive, so
+/// block `i` carries `seq + i`.  An empty response is a single empty block.
+pub fn response_blocks(
+    slot: u8,
+    seq: u8,
+    icc_status: IccStatus,
+    data: &[u8],
+) -> impl Iterator<Item = DataBlock<'_>> {
+    const CHUNK_LEN: usize = PACKET_SIZE - CCID_HEADER_LEN;
+    let count = data.len().div_ceil(CHUNK_LEN).max(1);
+    (0..count).map(move |i| {
+        let chunk = &data[i * CHUNK_LEN..];
+        let chunk = &chunk[..chunk.len().min(CHUNK_LEN)];
+        let chain = match (i == 0, i + 1 == count) {
+            (true, true) => Chain::BeginsAndEnds,
+            (true, false) => Chain::Begins,
+            (false, true) => Chain::Ends,
+            (false, false) => Chain::Continues,
+        };
+        DataBlock::new(slot, seq.wrapping_add(i as u8), icc_status, chain, chunk)
+    })
+}
+
 impl core::fmt::Debug for DataBlock<'_> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let mut debug_struct = f.debug_struct("DataBlock");
 
-        debug_struct.field("seq", &self.seq);
+        debug_struct
+            .field("slot", &self.slot)
+            .field("seq", &self.seq)
+            .field("icc_status", &self.icc_status);
 
         let l = core::cmp::min(self.data.len(), 16);
         let escaped_bytes: heapless::Vec<u8, 64> = self
@@ -136,11 +214,11 @@ impl From<DataBlock<'_>> for RawPacket {
                 .expect("Packets should not be more than 4GiB")
                 .to_le_bytes(),
         );
-        packet[5] = 0;
+        packet[5] = block.slot;
         packet[6] = block.seq;
 
-        // status
-        packet[7] = 0;
+        // status: command processed without error
+        packet[7] = block.icc_status as u8;
         // error
         packet[8] = 0;
         // chain parameter
@@ -153,25 +231,26 @@ impl From<DataBlock<'_>> for RawPacket {
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum CommandType {
     // REQUESTS
 
     // supported
+    SetParameters = 0x61,
     PowerOn = 0x62,
     PowerOff = 0x63,
     GetSlotStatus = 0x65,
+    Secure = 0x69, // PIN verification and modification
+    T0Apdu = 0x6a,
+    Escape = 0x6b, //  for vendor commands
     GetParameters = 0x6c,
     XfrBlock = 0x6f,
+    Mechanical = 0x71,
     Abort = 0x72,
+    SetDataRateAndClockFrequency = 0x73,
     // unsupported
     // ResetParameters = 0x6d,
-    // SetParameters = 0x61,
-    // Escape = 0x6b, //  for vendor commands
     // IccClock = 0x7e,
-    // T0Apdu = 0x6a,
-    // Secure = 0x69,
-    // Mechanical = 0x71,
-    // SetDataRateAndClockFrequency = 0x73,
 }
 
 macro_rules! command_message {
@@ -179,13 +258,13 @@ macro_rules! command_message {
     ($($Name:ident: $code:expr,)*) => {
         $(
             #[derive(Debug)]
-            pub struct $Name {
+            pub struct $Name<const M: usize = MAX_MSG_LENGTH> {
                 // use reference? pulls in lifetimes though...
-                ext_raw: ExtPacket,
+                ext_raw: ExtPacket<M>,
             }
 
-            impl core::ops::Deref for $Name {
-                type Target = ExtPacket;
+            impl<const M: usize> core::ops::Deref for $Name<M> {
+                type Target = ExtPacket<M>;
 
                 #[inline]
                 fn deref(&self) -> &Self::Target {
@@ -193,7 +272,7 @@ macro_rules! command_message {
                 }
             }
 
-            impl core::ops::DerefMut for $Name {
+            impl<const M: usize> core::ops::DerefMut for $Name<M> {
 
                 #[inline]
                 fn deref_mut(&mut self) -> &mut Self::Target {
@@ -201,16 +280,24 @@ macro_rules! command_message {
                 }
             }
 
-            impl Packet for $Name {}
+            impl<const M: usize> Packet<M> for $Name<M> {}
         )*
 
-        pub enum Command {
+        pub enum Command<const M: usize = MAX_MSG_LENGTH> {
             $(
-                $Name($Name),
+                $Name($Name<M>),
             )*
         }
 
-        impl Command {
+        impl<const M: usize> Command<M> {
+            pub fn slot(&self) -> u8 {
+                match self {
+                    $(
+                        Command::$Name(packet) => packet.slot(),
+                    )*
+                }
+            }
+
             pub fn seq(&self) -> u8 {
                 match self {
                     $(
@@ -228,19 +315,16 @@ macro_rules! command_message {
             }
         }
 
-        impl core::convert::TryFrom<ExtPacket> for Command {
+        impl<const M: usize> core::convert::TryFrom<ExtPacket<M>> for Command<M> {
             type Error = Error;
 
             #[inline]
-            fn try_from(packet: ExtPacket)
+            fn try_from(packet: ExtPacket<M>)
                 -> core::result::Result<Self, Self::Error>
             {
                 if packet.len() < CCID_HEADER_LEN {
                     return Err(Error::ShortPacket);
                 }
-                if packet[5] != 0 {
-                    // wrong slot
-                }
                 let command_byte = packet[0];
                 Ok(match command_byte {
                     $(
@@ -251,8 +335,8 @@ macro_rules! command_message {
             }
         }
 
-        impl core::ops::Deref for Command {
-            type Target = ExtPacket;
+        impl<const M: usize> core::ops::Deref for Command<M> {
+            type Target = ExtPacket<M>;
 
             #[inline]
             fn deref(&self) -> &Self::Target {
@@ -279,17 +363,79 @@ macro_rules! command_message {
 }
 
 command_message!(
+    SetParameters: 0x61,
     PowerOn: 0x62,
     PowerOff: 0x63,
     GetSlotStatus: 0x65,
+    Secure: 0x69,
+    T0Apdu: 0x6a,
+    Escape: 0x6b,
     GetParameters: 0x6c,
     XfrBlock: 0x6f,
+    Mechanical: 0x71,
     Abort: 0x72,
+    SetDataRateAndClockFrequency: 0x73,
 );
 
-impl PacketWithData for XfrBlock {}
+/// Parse a complete PC_to_RDR message, e.g. in host-side simulators and tests.
+///
+/// Messages longer than `M` are rejected with [`Error::MessageTooLong`].  The length of the
+/// message is not checked against dwLength, the data accessors only return the bytes present.
+pub fn parse_command<const M: usize>(bytes: &[u8]) -> Result<Command<M>, Error> {
+    let packet = ExtPacket::<M>::from_slice(bytes).map_err(|_| Error::MessageTooLong)?;
+    Command::try_from(packet)
+}
+
+impl<const M: usize> PacketWithData<M> for XfrBlock<M> {}
+impl<const M: usize> PacketWithData<M> for Escape<M> {}
+impl<const M: usize> PacketWithData<M> for Secure<M> {}
+impl<const M: usize> PacketWithData<M> for SetDataRateAndClockFrequency<M> {}
+
+impl<const M: usize> SetParameters<M> {
+    /// bProtocolNum
+    #[inline]
+    pub fn protocol(&self) -> u8 {
+        self[7]
+    }
+
+    /// abProtocolDataStructure, as declared by dwLength and limited to the received bytes
+    pub fn protocol_data(&self) -> &[u8] {
+        let declared_len = u32::from_le_bytes(self[1..5].try_into().unwrap()) as usize;
+        let data = &self[CCID_HEADER_LEN..];
+        &data[..declared_len.min(data.len())]
+    }
+}
+
+impl<const M: usize> Mechanical<M> {
+    /// bFunction: accept, eject, capture, lock or unlock the card (1 to 5)
+    #[inline]
+    pub fn function(&self) -> u8 {
+        self[7]
+    }
+}
+
+impl<const M: usize> T0Apdu<M> {
+    /// bmChanges: bit 0 selects bClassGetResponse, bit 1 bClassEnvelope
+    #[inline]
+    pub fn changes(&self) -> u8 {
+        self[7]
+    }
+
+    /// bClassGetResponse, 0xFF to echo the class byte of the command
+    #[inline]
+    pub fn class_get_response(&self) -> u8 {
+        self[8]
+    }
+
+    /// bClassEnvelope, 0xFF to echo the class byte of the command
+    #[inline]
+    pub fn class_envelope(&self) -> u8 {
+        self[9]
+    }
+}
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum Chain {
     BeginsAndEnds = 0,
@@ -308,7 +454,7 @@ impl Chain {
     }
 }
 
-impl core::fmt::Debug for Command {
+impl<const M: usize> core::fmt::Debug for Command<M> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let mut debug_struct = f.debug_struct("Command");
         // write!("Command({:?})", &self.command_type()));
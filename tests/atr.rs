@@ -0,0 +1,146 @@
+use usbd_ccid::{AtrBuilder, AtrError};
+
+#[test]
+fn card_issuers_data() {
+    // the Solo 2 ATR
+    let atr = AtrBuilder::new()
+        .card_issuers_data(b"Solo 2")
+        .build()
+        .unwrap();
+    assert_eq!(
+        &atr[..],
+        &[0x3B, 0x88, 0x01, 0x80, 0x56, 0x53, 0x6F, 0x6C, 0x6F, 0x20, 0x32, 0x72]
+    );
+}
+
+#[test]
+fn checksum() {
+    for atr in [
+        AtrBuilder::new().build().unwrap(),
+        AtrBuilder::new()
+            .historical_bytes(&[1, 2, 3])
+            .build()
+            .unwrap(),
+        AtrBuilder::new()
+            .inverse_convention()
+            .card_issuers_data(b"Solo 2")
+            .build()
+            .unwrap(),
+    ] {
+        // TCK makes the xor of all bytes except TS zero
+        assert_eq!(atr.iter().skip(1).fold(0, |acc, byte| acc ^ byte), 0);
+    }
+}
+
+#[test]
+fn too_many_historical_bytes() {
+    assert_eq!(
+        AtrBuilder::new().historical_bytes(&[0; 16]).build(),
+        Err(AtrError::HistoricalBytesTooLong)
+    );
+    assert_eq!(
+        AtrBuilder::new().card_issuers_data(&[0; 14]).build(),
+        Err(AtrError::HistoricalBytesTooLong)
+    );
+}
+
+#[test]
+fn fast_timing() {
+    let builder = AtrBuilder::new().fast_timing().card_issuers_data(b"Solo 2");
+    let atr = builder.build().unwrap();
+    assert_eq!(
+        &atr[..],
+        &[0x3B, 0x98, 0x96, 0x01, 0x80, 0x56, 0x53, 0x6F, 0x6C, 0x6F, 0x20, 0x32, 0xF4]
+    );
+    assert_eq!(builder.fi_di(), 0x96);
+    assert_eq!(AtrBuilder::new().fi_di(), 0x11);
+}
+
+// The TCK of an ATR announcing T=1 with TD1, as built by `AtrBuilder`.
+fn tck(atr: &[u8]) -> u8 {
+    atr[1..atr.len() - 1].iter().fold(0, |tck, byte| tck ^ byte)
+}
+
+#[test]
+fn card_capabilities() {
+    // YubiKey 5: 3B 8D 80 01 80 73 C0 21 C0 57 59 75 62 69 4B 65 79 F9
+    let historical_bytes = [
+        0x80, 0x73, 0xC0, 0x21, 0xC0, 0x57, 0x59, 0x75, 0x62, 0x69, 0x4B, 0x65, 0x79,
+    ];
+    let atr = AtrBuilder::new()
+        .card_capabilities(&[0xC0, 0x21, 0xC0])
+        .card_issuers_data(b"YubiKey")
+        .build()
+        .unwrap();
+    assert_eq!(&atr[..3], [0x3B, 0x8D, 0x01]);
+    assert_eq!(&atr[3..16], historical_bytes);
+    assert_eq!(atr[16], tck(&atr));
+    assert_eq!(atr.len(), 17);
+}
+
+#[test]
+fn card_service_data() {
+    // German eID: 3B 8A 80 01 80 31 F8 73 F7 41 E0 82 90 00 75, without the status indicator
+    let atr = AtrBuilder::new()
+        .card_service_data(0xF8)
+        .card_capabilities(&[0xF7, 0x41, 0xE0])
+        .build()
+        .unwrap();
+    assert_eq!(
+        &atr[..],
+        [0x3B, 0x87, 0x01, 0x80, 0x31, 0xF8, 0x73, 0xF7, 0x41, 0xE0, 0xEA]
+    );
+
+    // raw historical bytes replace the objects
+    let atr = AtrBuilder::new()
+        .card_service_data(0xF8)
+        .historical_bytes(&[0x42])
+        .build()
+        .unwrap();
+    assert_eq!(&atr[..], [0x3B, 0x81, 0x01, 0x42, 0xC2]);
+}
+
+#[test]
+fn invalid_card_capabilities() {
+    for capabilities in [&[][..], &[0; 4]] {
+        assert_eq!(
+            AtrBuilder::new().card_capabilities(capabilities).build(),
+            Err(AtrError::InvalidCardCapabilities)
+        );
+    }
+}
+
+#[test]
+fn t0_omits_checksum() {
+    let atr = AtrBuilder::new().protocol_t0().build().unwrap();
+    assert_eq!(&atr[..], &[0x3B, 0x80, 0x00]);
+}
+
+#[test]
+fn status_indicator() {
+    let atr = AtrBuilder::new()
+        .card_issuers_data(b"AB")
+        .status_indicator([0x00, 0x90, 0x00])
+        .build()
+        .unwrap();
+    assert_eq!(
+        &atr[..],
+        &[0x3B, 0x88, 0x01, 0x80, 0x52, b'A', b'B', 0x83, 0x00, 0x90, 0x00, 0x4B]
+    );
+
+    // ignored without COMPACT-TLV objects
+    let atr = AtrBuilder::new()
+        .status_indicator([0x00, 0x90, 0x00])
+        .build()
+        .unwrap();
+    assert_eq!(&atr[..], &[0x3B, 0x80, 0x01, 0x81]);
+
+    // counted against the historical bytes
+    assert_eq!(
+        AtrBuilder::new()
+            .card_issuers_data(&[0; 10])
+            .status_indicator([0x00, 0x90, 0x00])
+            .build(),
+        Err(AtrError::HistoricalBytesTooLong)
+    );
+}
@@ -0,0 +1,362 @@
+mod common;
+
+use common::{
+    allocator, message, power_on, status, with_builder, with_ccid, xfr_block, Channel, HEADER_LEN,
+    PACKET_SIZE,
+};
+use usbd_ccid::{
+    AtrBuilder, CardEvent, CcidBuilder, CcidDescriptor, CcidError, ConfigError, ExtendedApduCcid,
+    Protocol, SeqPolicy, State, EXTENDED_APDU_LEN,
+};
+
+#[test]
+fn config_snapshot() {
+    let builder = CcidBuilder::new()
+        .card_issuers_data(b"Solo 2")
+        .max_message_length(1024);
+    with_builder::<EXTENDED_APDU_LEN, 2048>(builder, |t| {
+        t.ccid.set_seq_policy(SeqPolicy::Strict);
+        t.ccid.set_num_slots(2).unwrap();
+
+        let mut buf = [0; 64];
+        let len = t.ccid.config_snapshot(&mut buf);
+        let snapshot = &buf[..len];
+        assert_eq!(snapshot[0], 1);
+        assert_eq!(snapshot[1], 2);
+        // bProtocolNum of T=1
+        assert_eq!(snapshot[2], 1);
+        assert_eq!(
+            u16::from_le_bytes([snapshot[3], snapshot[4]]) as usize,
+            PACKET_SIZE
+        );
+        assert_eq!(u32::from_le_bytes(snapshot[5..9].try_into().unwrap()), 1024);
+        assert_eq!(
+            u32::from_le_bytes(snapshot[9..13].try_into().unwrap()),
+            t.ccid.features()
+        );
+        assert_eq!(snapshot[13], SeqPolicy::Strict as u8);
+        assert_eq!(
+            &snapshot[14..21],
+            &[0x11, 0x10, 0x00, 0x15, 0x00, 0xfe, 0x00]
+        );
+        let atr = &snapshot[22..];
+        assert_eq!(snapshot[21] as usize, atr.len());
+        assert_eq!(
+            atr,
+            [0x3B, 0x88, 0x01, 0x80, 0x56, 0x53, 0x6F, 0x6C, 0x6F, 0x20, 0x32, 0x72]
+        );
+
+        // too small a buffer is left alone
+        assert_eq!(t.ccid.config_snapshot(&mut buf[..len - 1]), 0);
+    });
+}
+
+#[test]
+fn fast_timing_agrees_with_parameters() {
+    for protocol in [Protocol::T1, Protocol::T0] {
+        let builder = CcidBuilder::new().protocol(protocol).fast_timing(true);
+        with_builder::<EXTENDED_APDU_LEN, 2048>(builder, |t| {
+            let atr = t.exchange(&power_on(0)).remove(0);
+            // T0 announces TA1
+            assert_eq!(atr[HEADER_LEN + 1] & 0x10, 0x10);
+            let ta1 = atr[HEADER_LEN + 2];
+            assert_eq!(ta1, AtrBuilder::new().fast_timing().fi_di());
+
+            let parameters = t.exchange(&message(0x6c, 0, 1, 0, &[])).remove(0);
+            assert_eq!(parameters[0], 0x82);
+            assert_eq!(parameters[HEADER_LEN], ta1);
+        });
+    }
+}
+
+#[test]
+fn last_response_was_error() {
+    with_ccid(|t| {
+        // unknown message type
+        let responses = t.exchange(&message(0x01, 0, 0, 0, &[]));
+        assert_eq!(status(&responses), [(0x81, 0x40, 0x00)]);
+        assert!(t.ccid.last_response_was_error());
+        assert_eq!(t.ccid.last_error(), Some(CcidError::CommandNotSupported));
+
+        t.send(&xfr_block(1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(t.receive().len(), 1);
+        assert!(!t.ccid.last_response_was_error());
+    });
+}
+
+#[test]
+fn abort_silent() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.ccid.abort_silent();
+        assert!(t.receive().is_empty());
+        assert_eq!(t.ccid.state(), State::Idle);
+        // the application is told to drop the command
+        assert!(t.responder().is_canceled());
+        t.responder().acknowledge_cancel().unwrap();
+
+        // a response being sent is dropped as well
+        t.send(&xfr_block(1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.answer(&[0x42; 2 * PACKET_SIZE]);
+        assert_eq!(t.take_written().len(), 1);
+        t.ccid.abort_silent();
+        t.complete();
+        assert!(t.receive().is_empty());
+
+        t.send(&xfr_block(2, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert!(t.take_request().is_some());
+    });
+}
+
+#[test]
+fn card_events() {
+    with_builder::<EXTENDED_APDU_LEN, 2048>(CcidBuilder::new().interrupt(true), |t| {
+        t.ccid.on_card_event(0, CardEvent::Removed).unwrap();
+        // bmSlotICCState: slot 0 changed, no ICC
+        assert_eq!(t.take_notifications(), [vec![0x50, 0b10]]);
+        t.ccid.on_card_event(0, CardEvent::Inserted).unwrap();
+        assert_eq!(t.take_notifications(), [vec![0x50, 0b11]]);
+
+        t.exchange(&power_on(5));
+        t.ccid
+            .on_card_event(0, CardEvent::HardwareError(0x01))
+            .unwrap();
+        // bSlot, the bSeq of the last command and bHardwareErrorCode
+        assert_eq!(t.take_notifications(), [vec![0x51, 0, 5, 0x01]]);
+        // the ICC is inactive
+        let responses = t.exchange(&message(0x65, 0, 6, 0, &[]));
+        assert_eq!(status(&responses), [(0x81, 0x01, 0)]);
+
+        assert_eq!(
+            t.ccid.on_card_event(1, CardEvent::HardwareError(0x01)),
+            Err(ConfigError::InvalidSlot)
+        );
+    });
+    with_ccid(|t| {
+        assert_eq!(
+            t.ccid.on_card_event(0, CardEvent::Removed),
+            Err(ConfigError::NoInterruptEndpoint)
+        );
+    });
+}
+
+#[test]
+fn set_features() {
+    with_ccid(|t| {
+        let descriptor = CcidDescriptor::parse(&t.ccid.functional_descriptor()).unwrap();
+        assert_eq!(descriptor.features, 0x0004_0840);
+
+        // short instead of extended APDU level exchange, without automatic parameter negotiation
+        t.ccid.set_features(0x0002_0800).unwrap();
+        assert_eq!(t.ccid.features(), 0x0002_0800);
+        let descriptor = CcidDescriptor::parse(&t.ccid.functional_descriptor()).unwrap();
+        assert_eq!(descriptor.features, 0x0002_0800);
+
+        // TPDU level exchange is not implemented
+        assert_eq!(
+            t.ccid.set_features(0x0001_0840),
+            Err(ConfigError::UnsupportedFeatures)
+        );
+        assert_eq!(t.ccid.features(), 0x0002_0800);
+    });
+}
+
+#[test]
+fn suggested_poll_delay() {
+    with_ccid(|t| {
+        assert_eq!(t.ccid.suggested_poll_delay(), None);
+
+        t.send(&xfr_block(0, 0, &[0x00, 0x2a, 0x9e, 0x9a]));
+        assert!(t.ccid.suggested_poll_delay().is_some());
+        t.take_request().unwrap();
+        assert!(t.ccid.suggested_poll_delay().is_some());
+
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(t.ccid.suggested_poll_delay(), None);
+        t.receive();
+        assert_eq!(t.ccid.suggested_poll_delay(), None);
+    });
+}
+
+#[test]
+fn send_atr_now() {
+    with_ccid(|t| {
+        let atr = t.exchange(&power_on(3)).remove(0);
+        assert_eq!(atr[6], 3);
+        assert_eq!(
+            &atr[HEADER_LEN..],
+            AtrBuilder::new().build().unwrap().as_slice()
+        );
+        assert!(t.ccid.send_atr_now());
+        assert_eq!(t.receive(), [atr]);
+
+        // not while a command is processed
+        t.send(&xfr_block(4, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert!(!t.ccid.send_atr_now());
+        assert!(t.receive().is_empty());
+    });
+}
+
+#[test]
+fn notify_slot_change() {
+    with_builder::<EXTENDED_APDU_LEN, 2048>(CcidBuilder::new().interrupt(true), |t| {
+        t.ccid.set_num_slots(2).unwrap();
+        t.ccid.notify_slot_change(1, false).unwrap();
+        // bmSlotICCState: slot 0 present, slot 1 changed and absent
+        assert_eq!(t.take_notifications(), [vec![0x50, 0b1001]]);
+        t.ccid.notify_slot_change(1, false).unwrap();
+        assert!(t.take_notifications().is_empty());
+
+        // toggles while the host has not fetched the last notification are coalesced
+        t.ccid.notify_slot_change(0, false).unwrap();
+        t.ccid.notify_slot_change(1, true).unwrap();
+        t.ccid.notify_slot_change(1, false).unwrap();
+        t.ccid.notify_slot_change(0, true).unwrap();
+        t.ccid.notify_slot_change(1, true).unwrap();
+        assert_eq!(t.take_notifications(), [vec![0x50, 0b10]]);
+        assert_eq!(t.take_notifications(), [vec![0x50, 0b1111]]);
+        assert!(t.take_notifications().is_empty());
+    });
+    with_ccid(|t| {
+        // without an interrupt endpoint, only the status is recorded
+        t.ccid.notify_slot_change(0, false).unwrap();
+        let responses = t.exchange(&message(0x65, 0, 0, 0, &[]));
+        assert_eq!(status(&responses), [(0x81, 0x02, 0)]);
+    });
+}
+
+#[test]
+fn atr_announces_the_protocol() {
+    for (protocol, atr) in [
+        (
+            Protocol::T0,
+            &[
+                0x3B, 0x88, 0x00, 0x80, 0x56, 0x53, 0x6F, 0x6C, 0x6F, 0x20, 0x32,
+            ][..],
+        ),
+        (
+            Protocol::T1,
+            &[
+                0x3B, 0x88, 0x01, 0x80, 0x56, 0x53, 0x6F, 0x6C, 0x6F, 0x20, 0x32, 0x72,
+            ],
+        ),
+    ] {
+        let builder = CcidBuilder::new()
+            .protocol(protocol)
+            .card_issuers_data(b"Solo 2");
+        with_builder::<EXTENDED_APDU_LEN, 2048>(builder, |t| {
+            let responses = t.exchange(&power_on(0));
+            assert_eq!(&responses[0][HEADER_LEN..], atr);
+
+            // bProtocolNum of the parameters, as in TD1
+            let responses = t.exchange(&message(0x6c, 0, 1, 0, &[]));
+            assert_eq!(responses[0][9], atr[2]);
+        });
+    }
+}
+
+#[test]
+fn t0_get_response() {
+    let builder = CcidBuilder::new().protocol(Protocol::T0);
+    with_builder::<EXTENDED_APDU_LEN, 2048>(builder, |t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xb0, 0x00, 0x00, 0x00]));
+        t.take_request().unwrap();
+        let mut response: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        response.extend_from_slice(&[0x90, 0x00]);
+        t.answer(&response);
+
+        let (first, seq) = t.receive_chain(0);
+        assert_eq!(first.len(), 258);
+        assert_eq!(&first[..256], &response[..256]);
+        // 44 bytes remain
+        assert_eq!(&first[256..], [0x61, 44]);
+
+        t.send(&xfr_block(seq + 1, 0, &[0x00, 0xc0, 0x00, 0x00, 44]));
+        assert!(t.take_request().is_none());
+        assert_eq!(t.receive_chain(seq + 1).0, &response[256..]);
+        assert_eq!(t.ccid.state(), State::Idle);
+    });
+}
+
+#[test]
+fn card_issuers_data_too_long() {
+    let allocator = allocator();
+    let channel = Channel::new();
+    let (requester, _responder) = channel.split().unwrap();
+    let ccid = ExtendedApduCcid::try_new(&allocator, requester, Some(&[0x42; 14]));
+    assert!(matches!(ccid, Err(ConfigError::IssuerDataTooLong)));
+
+    let builder = CcidBuilder::new().card_issuers_data(&[0x42; 13]);
+    with_builder::<EXTENDED_APDU_LEN, 2048>(builder, |t| {
+        let atr = t.exchange(&power_on(0)).remove(0);
+        // TS, T0, TD1, the category indicator and tag, the data and TCK
+        assert_eq!(atr.len(), HEADER_LEN + 19);
+    });
+}
+
+#[test]
+fn atr_data_block() {
+    with_ccid(|t| {
+        let atr = AtrBuilder::new().build().unwrap();
+        let responses = t.exchange(&power_on(7));
+        let mut expected = vec![0x80, atr.len() as u8, 0, 0, 0, 0, 7, 0, 0, 0];
+        expected.extend_from_slice(&atr);
+        assert_eq!(responses, [expected]);
+    });
+}
+
+#[test]
+fn set_atr() {
+    with_ccid(|t| {
+        let atr = AtrBuilder::new().card_issuers_data(b"PIV").build().unwrap();
+        t.ccid.set_atr(0, &atr).unwrap();
+        let responses = t.exchange(&power_on(0));
+        assert_eq!(responses, [message(0x80, 0, 0, 0, &atr)]);
+
+        assert_eq!(t.ccid.set_atr(0, &[0x3b; 34]), Err(ConfigError::AtrTooLong));
+        assert_eq!(t.ccid.set_atr(1, &atr), Err(ConfigError::InvalidSlot));
+        // not while a transfer is in progress
+        t.exchange(&xfr_block(1, 0x01, &[1, 2, 3]));
+        assert_eq!(t.ccid.set_atr(0, &atr), Err(ConfigError::SlotBusy));
+    });
+}
+
+#[test]
+fn set_card_issuers_data() {
+    with_ccid(|t| {
+        t.ccid.set_card_issuers_data(0, Some(b"Solo 2")).unwrap();
+        let responses = t.exchange(&power_on(0));
+        assert_eq!(
+            &responses[0][HEADER_LEN..],
+            [0x3B, 0x88, 0x01, 0x80, 0x56, 0x53, 0x6F, 0x6C, 0x6F, 0x20, 0x32, 0x72]
+        );
+
+        assert_eq!(
+            t.ccid.set_card_issuers_data(0, Some(&[0x42; 14])),
+            Err(ConfigError::IssuerDataTooLong)
+        );
+        assert_eq!(
+            t.ccid.set_card_issuers_data(1, None),
+            Err(ConfigError::InvalidSlot)
+        );
+        // not while a transfer is in progress
+        t.exchange(&xfr_block(1, 0x01, &[1, 2, 3]));
+        assert_eq!(
+            t.ccid.set_card_issuers_data(0, None),
+            Err(ConfigError::SlotBusy)
+        );
+        t.send(&xfr_block(2, 0x02, &[]));
+        t.take_request().unwrap();
+        t.answer(&[0x90, 0x00]);
+        t.receive();
+
+        // without card issuer's data
+        t.ccid.set_card_issuers_data(0, None).unwrap();
+        let responses = t.exchange(&power_on(3));
+        assert_eq!(&responses[0][HEADER_LEN..], [0x3B, 0x80, 0x01, 0x81]);
+    });
+}
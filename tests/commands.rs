@@ -0,0 +1,320 @@
+mod common;
+
+use common::{
+    message, power_on, status, with_builder, with_ccid, xfr_block, HEADER_LEN, PACKET_SIZE,
+};
+use usbd_ccid::{
+    CcidBuilder, CcidError, ConfigError, IccStatus, Parameters, State, T1Parameters,
+    EXTENDED_APDU_LEN,
+};
+
+#[test]
+fn empty_command() {
+    with_ccid(|t| {
+        let responses = t.exchange(&xfr_block(0, 0, &[]));
+        assert_eq!(status(&responses), [(0x80, 0, 0)]);
+        assert_eq!(&responses[0][HEADER_LEN..], [0x67, 0x00]);
+        assert!(t.take_request().is_none());
+        assert_eq!(t.ccid.state(), State::Idle);
+
+        // the same for a chain without data
+        t.exchange(&xfr_block(1, 0x01, &[]));
+        let responses = t.exchange(&xfr_block(2, 0x02, &[]));
+        assert_eq!(&responses[0][HEADER_LEN..], [0x67, 0x00]);
+        assert!(t.take_request().is_none());
+    });
+}
+
+#[test]
+fn not_ready() {
+    with_ccid(|t| {
+        t.ccid.set_ready(false);
+        let responses = t.exchange(&xfr_block(0, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(status(&responses), [(0x80, 0x40, 0xe0)]);
+        let responses = t.exchange(&message(0x65, 0, 1, 0, &[]));
+        assert_eq!(status(&responses), [(0x81, 0x40, 0xe0)]);
+        assert!(t.take_request().is_none());
+
+        // PowerOn is still answered with the ATR
+        let responses = t.exchange(&power_on(2));
+        assert_eq!(status(&responses), [(0x80, 0, 0)]);
+
+        t.ccid.set_ready(true);
+        t.send(&xfr_block(3, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn set_parameters() {
+    with_ccid(|t| {
+        let defaults = T1Parameters::default();
+        assert_eq!(t.ccid.parameters(0), Ok(Parameters::T1(defaults)));
+        assert_eq!(t.ccid.parameters(1), Err(ConfigError::InvalidSlot));
+
+        let parameters = T1Parameters {
+            ifsc: 0x80,
+            ..defaults
+        };
+        let mut command = message(0x61, 0, 0, 0, &parameters.to_bytes());
+        // bProtocolNum: T=1
+        command[7] = 1;
+        let responses = t.exchange(&command);
+        assert_eq!(status(&responses), [(0x82, 0, 0)]);
+        assert_eq!(&responses[0][HEADER_LEN..], parameters.to_bytes());
+
+        let parameters = t.ccid.parameters(0).unwrap();
+        assert_eq!(
+            parameters,
+            Parameters::T1(T1Parameters {
+                ifsc: 0x80,
+                ..defaults
+            })
+        );
+        // the IFSD is not negotiated, the default is reported
+        assert_eq!(parameters.ifsd(), Some(254));
+    });
+}
+
+#[test]
+fn protocol_switch_is_rejected() {
+    with_ccid(|t| {
+        t.exchange(&power_on(0));
+
+        // T=0 parameters on a T=1 reader, after the ATR announced T=1
+        let command = message(0x61, 0, 1, 0, &[0x11, 0x00, 0x00, 0x0a, 0x00]);
+        let responses = t.exchange(&command);
+        // bError points at bProtocolNum, which is the protocol in use
+        assert_eq!(status(&responses), [(0x82, 0x40, 7)]);
+        assert_eq!(responses[0][9], 1);
+        assert_eq!(
+            t.ccid.parameters(0),
+            Ok(Parameters::T1(T1Parameters::default()))
+        );
+
+        t.send(&xfr_block(2, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn get_parameters_before_power_on() {
+    with_ccid(|t| {
+        let responses = t.exchange(&message(0x6c, 0, 0, 0, &[]));
+        assert_eq!(status(&responses), [(0x82, 0, 0)]);
+        assert_eq!(responses[0][9], 1);
+        assert_eq!(
+            &responses[0][HEADER_LEN..],
+            T1Parameters::default().to_bytes()
+        );
+    });
+
+    with_builder::<EXTENDED_APDU_LEN, 3072>(
+        CcidBuilder::new().parameters_require_power(true),
+        |t| {
+            let responses = t.exchange(&message(0x6c, 0, 0, 0, &[]));
+            assert_eq!(status(&responses), [(0x82, 0x40, 0xfe)]);
+            assert_eq!(responses[0].len(), HEADER_LEN);
+
+            t.exchange(&power_on(1));
+            let responses = t.exchange(&message(0x6c, 0, 2, 0, &[]));
+            assert_eq!(status(&responses), [(0x82, 0, 0)]);
+            assert_eq!(
+                &responses[0][HEADER_LEN..],
+                T1Parameters::default().to_bytes()
+            );
+        },
+    );
+}
+
+#[test]
+fn power_on_while_receiving() {
+    with_ccid(|t| {
+        t.exchange(&xfr_block(0, 0x01, &[1, 2, 3]));
+        assert_eq!(t.ccid.state(), State::Receiving);
+
+        let responses = t.exchange(&power_on(1));
+        assert_eq!(status(&responses), [(0x80, 0, 0)]);
+        assert!(responses[0].len() > HEADER_LEN);
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert!(t.take_request().is_none());
+
+        // the partial command is gone
+        t.send(&xfr_block(2, 0, &[9]));
+        assert_eq!(t.take_request().unwrap(), [9]);
+    });
+}
+
+#[test]
+fn power_on_while_sending() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xb0, 0x00, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.answer(&[0x42; 2 * PACKET_SIZE]);
+        let blocks = t.receive();
+        assert_eq!(blocks[0][9], 0x01);
+        assert_eq!(t.ccid.state(), State::Sending);
+
+        // the host gives up on the response and restarts
+        let responses = t.exchange(&power_on(1));
+        assert_eq!(status(&responses), [(0x80, 0, 0)]);
+        assert_eq!(responses[0][6], 1);
+        assert_eq!(t.ccid.state(), State::Idle);
+
+        t.send(&xfr_block(2, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(
+            t.receive(),
+            [vec![0x80, 2, 0, 0, 0, 0, 2, 0, 0, 0, 0x90, 0x00]]
+        );
+    });
+}
+
+#[test]
+fn application_signals_parity_error() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xb0, 0x00, 0x00, 0x00]));
+        t.take_request().unwrap();
+        // a single byte response is the bError of a failed DataBlock
+        t.answer(&[0xfd]);
+        let responses = t.receive();
+        assert_eq!(status(&responses), [(0x80, 0x40, 0xfd)]);
+        assert_eq!(responses[0].len(), HEADER_LEN);
+        assert_eq!(t.ccid.last_error(), Some(CcidError::XfrParityError));
+        assert_eq!(t.ccid.state(), State::Idle);
+    });
+}
+
+#[test]
+fn set_parameters_rejects_unsupported_values() {
+    with_ccid(|t| {
+        // bmTCCKST1: inverse convention
+        let mut command = message(0x61, 0, 0, 0, &[0x11, 0x12, 0x00, 0x4d, 0x00, 0xfe, 0x00]);
+        command[7] = 1;
+        let responses = t.exchange(&command);
+        // bError is the offset of the rejected byte
+        assert_eq!(status(&responses), [(0x82, 0x40, 11)]);
+
+        // bmFindexDindex: Fi = 7 is RFU
+        let mut command = message(0x61, 0, 0, 0, &[0x71, 0x10, 0x00, 0x4d, 0x00, 0xfe, 0x00]);
+        command[7] = 1;
+        let responses = t.exchange(&command);
+        assert_eq!(status(&responses), [(0x82, 0x40, 10)]);
+
+        // abProtocolDataStructure of T=0 for T=1
+        let mut command = message(0x61, 0, 1, 0, &[0x11, 0x00, 0x00, 0x0a, 0x00]);
+        command[7] = 1;
+        let responses = t.exchange(&command);
+        assert_eq!(status(&responses), [(0x82, 0x40, 10)]);
+        assert_eq!(
+            t.ccid.parameters(0),
+            Ok(Parameters::T1(T1Parameters::default()))
+        );
+    });
+}
+
+// Answers with the number of bytes requested in the first two bytes (little endian).
+fn counting_escape(command: &[u8], response: &mut [u8]) -> usize {
+    let len = u16::from_le_bytes([command[0], command[1]]) as usize;
+    for (i, byte) in response.iter_mut().take(len).enumerate() {
+        *byte = i as u8;
+    }
+    len
+}
+
+#[test]
+fn escape_spanning_several_packets() {
+    with_ccid(|t| {
+        t.ccid.set_escape_handler(Some(counting_escape));
+
+        let len = 2 * PACKET_SIZE + 5;
+        let packets = t.exchange(&message(0x6b, 0, 0, 0, &(len as u16).to_le_bytes()));
+        let sizes: Vec<_> = packets.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [PACKET_SIZE, PACKET_SIZE, HEADER_LEN + 5]);
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        assert_eq!(packets.concat(), message(0x83, 0, 0, 0, &data));
+
+        // a message ending with a full packet is terminated with a ZLP
+        let len = 2 * PACKET_SIZE - HEADER_LEN;
+        let packets = t.exchange(&message(0x6b, 0, 1, 0, &(len as u16).to_le_bytes()));
+        let sizes: Vec<_> = packets.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [PACKET_SIZE, PACKET_SIZE, 0]);
+        assert_eq!(packets.concat().len(), HEADER_LEN + len);
+    });
+}
+
+#[test]
+fn escape_longer_than_a_message() {
+    with_builder::<EXTENDED_APDU_LEN, 3072>(CcidBuilder::new().max_message_length(1024), |t| {
+        t.ccid.set_escape_handler(Some(counting_escape));
+        let len = 1024 - HEADER_LEN;
+        let packets = t.exchange(&message(0x6b, 0, 0, 0, &(len as u16).to_le_bytes()));
+        assert_eq!(packets.concat().len(), 1024);
+
+        let len = 1024 - HEADER_LEN + 1;
+        let responses = t.exchange(&message(0x6b, 0, 1, 0, &(len as u16).to_le_bytes()));
+        assert_eq!(status(&responses), [(0x83, 0x40, 0xff)]);
+    });
+}
+
+#[test]
+fn icc_status() {
+    with_ccid(|t| {
+        t.exchange(&power_on(0));
+        let responses = t.exchange(&message(0x65, 0, 1, 0, &[]));
+        assert_eq!(status(&responses), [(0x81, 0x00, 0)]);
+
+        // PowerOff: present, inactive
+        let responses = t.exchange(&message(0x63, 0, 2, 0, &[]));
+        assert_eq!(status(&responses), [(0x81, 0x01, 0)]);
+        let responses = t.exchange(&message(0x65, 0, 3, 0, &[]));
+        assert_eq!(responses[0][7], 0x01);
+
+        // removed: no ICC, and PowerOn fails with IccMute
+        t.ccid.set_icc_status(0, IccStatus::NotPresent).unwrap();
+        let responses = t.exchange(&message(0x65, 0, 4, 0, &[]));
+        assert_eq!(responses[0][7], 0x02);
+        let responses = t.exchange(&power_on(5));
+        assert_eq!(status(&responses), [(0x80, 0x42, 0xfe)]);
+    });
+}
+
+#[test]
+fn mechanical() {
+    with_ccid(|t| {
+        // accept, eject, capture, lock and unlock are no-ops
+        for function in 1..=5 {
+            let mut command = message(0x71, 0, function, 0, &[]);
+            command[7] = function;
+            assert_eq!(status(&t.exchange(&command)), [(0x81, 0, 0)]);
+        }
+        let mut command = message(0x71, 0, 6, 0, &[]);
+        command[7] = 6;
+        assert_eq!(status(&t.exchange(&command)), [(0x81, 0x40, 0x07)]);
+    });
+}
+
+#[test]
+fn set_data_rate_and_clock_frequency() {
+    with_ccid(|t| {
+        // 3.58 MHz, 9600 bps: the advertised values
+        let advertised = [0xfc, 0x0d, 0, 0, 0x80, 0x25, 0, 0];
+        let responses = t.exchange(&message(0x73, 0, 0, 0, &advertised));
+        assert_eq!(responses, [message(0x84, 0, 0, 0, &advertised)]);
+
+        // other values cannot be set, the advertised ones are reported
+        let responses = t.exchange(&message(0x73, 0, 1, 0, &[0x40, 0x1f, 0, 0, 0, 0x4b, 0, 0]));
+        assert_eq!(responses, [message(0x84, 0, 1, 0, &advertised)]);
+    });
+}
+
+#[test]
+fn t0_apdu_is_acknowledged() {
+    with_ccid(|t| {
+        let mut command = message(0x6a, 0, 0, 0, &[]);
+        command[7..10].copy_from_slice(&[0x03, 0xff, 0x80]);
+        assert_eq!(status(&t.exchange(&command)), [(0x81, 0, 0)]);
+        assert_eq!(t.ccid.reset_count(), 0);
+    });
+}
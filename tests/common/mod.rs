@@ -0,0 +1,380 @@
+//! Mock USB bus and harness for driving the class like a host would.
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use usb_device::bus::PollResult as BusPollResult;
+use usb_device::class_prelude::{EndpointAddress, EndpointType, UsbBus, UsbBusAllocator, UsbClass};
+use usb_device::device::UsbDevice;
+use usb_device::prelude::{UsbDeviceBuilder, UsbVidPid};
+use usb_device::{UsbDirection, UsbError};
+use usbd_ccid::{Ccid, CcidBuilder, ExtendedApduCcid, EXTENDED_APDU_LEN};
+
+pub use usbd_ccid::PACKET_SIZE;
+
+/// The CCID message header preceding the data of every bulk message.
+pub const HEADER_LEN: usize = 10;
+
+pub type Data<const N: usize> = iso7816::Data<N>;
+pub type Channel<const N: usize> = interchange::Channel<Data<N>, Data<N>>;
+pub type Requester<'pipe, const N: usize> = interchange::Requester<'pipe, Data<N>, Data<N>>;
+pub type Responder<'pipe, const N: usize> = interchange::Responder<'pipe, Data<N>, Data<N>>;
+
+/// State of the mock bus, shared between the bus and the test.
+#[derive(Default)]
+struct Endpoints {
+    next_index: usize,
+    bulk_out: Option<EndpointAddress>,
+    bulk_in: Option<EndpointAddress>,
+    interrupt: Option<EndpointAddress>,
+    /// Packets queued for the bulk OUT endpoint.
+    out: VecDeque<Vec<u8>>,
+    /// Setup packets queued for the control endpoint.
+    setup: VecDeque<[u8; 8]>,
+    /// Packets written to the bulk IN endpoint.
+    written: Vec<Vec<u8>>,
+    /// Packets written to the interrupt IN endpoint.
+    notifications: Vec<Vec<u8>>,
+    /// The last packet written to the bulk IN endpoint was not fetched yet.
+    in_busy: bool,
+    /// The last notification was not fetched yet.
+    interrupt_busy: bool,
+    /// The number of bulk IN writes to refuse with `WouldBlock`.
+    blocked_writes: usize,
+    /// Fail bulk IN writes with `InvalidState`.
+    failing_writes: bool,
+    stalled: Vec<EndpointAddress>,
+}
+
+/// Host side of the mock bus.
+#[derive(Clone, Default)]
+pub struct Host(Arc<Mutex<Endpoints>>);
+
+impl Host {
+    fn endpoints(&self) -> std::sync::MutexGuard<'_, Endpoints> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn bulk_out(&self) -> EndpointAddress {
+        self.endpoints().bulk_out.unwrap()
+    }
+
+    pub fn bulk_in(&self) -> EndpointAddress {
+        self.endpoints().bulk_in.unwrap()
+    }
+
+    pub fn interrupt(&self) -> Option<EndpointAddress> {
+        self.endpoints().interrupt
+    }
+
+    /// Refuse the next `count` bulk IN writes with `WouldBlock`.
+    pub fn block_writes(&self, count: usize) {
+        self.endpoints().blocked_writes = count;
+    }
+
+    /// Fail all bulk IN writes until called with `false`.
+    pub fn fail_writes(&self, failing: bool) {
+        self.endpoints().failing_writes = failing;
+    }
+
+    pub fn is_stalled(&self, address: EndpointAddress) -> bool {
+        self.endpoints().stalled.contains(&address)
+    }
+}
+
+pub struct MockBus(Host);
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        let mut endpoints = self.0.endpoints();
+        let address = match ep_addr {
+            Some(address) => address,
+            None => {
+                endpoints.next_index += 1;
+                EndpointAddress::from_parts(endpoints.next_index, ep_dir)
+            }
+        };
+        match (ep_type, ep_dir) {
+            (EndpointType::Bulk, UsbDirection::Out) => endpoints.bulk_out = Some(address),
+            (EndpointType::Bulk, UsbDirection::In) => endpoints.bulk_in = Some(address),
+            (EndpointType::Interrupt, UsbDirection::In) => endpoints.interrupt = Some(address),
+            _ => {}
+        }
+        Ok(address)
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        let mut endpoints = self.0.endpoints();
+        if Some(ep_addr) == endpoints.bulk_in {
+            if endpoints.failing_writes {
+                return Err(UsbError::InvalidState);
+            }
+            if endpoints.in_busy || endpoints.blocked_writes > 0 {
+                endpoints.blocked_writes = endpoints.blocked_writes.saturating_sub(1);
+                return Err(UsbError::WouldBlock);
+            }
+            endpoints.in_busy = true;
+            endpoints.written.push(buf.to_vec());
+        } else if Some(ep_addr) == endpoints.interrupt {
+            if endpoints.interrupt_busy {
+                return Err(UsbError::WouldBlock);
+            }
+            endpoints.interrupt_busy = true;
+            endpoints.notifications.push(buf.to_vec());
+        }
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        let mut endpoints = self.0.endpoints();
+        if ep_addr.index() == 0 {
+            let setup = endpoints.setup.pop_front().ok_or(UsbError::WouldBlock)?;
+            buf[..setup.len()].copy_from_slice(&setup);
+            return Ok(setup.len());
+        }
+        if Some(ep_addr) != endpoints.bulk_out {
+            return Err(UsbError::WouldBlock);
+        }
+        let packet = endpoints.out.pop_front().ok_or(UsbError::WouldBlock)?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut endpoints = self.0.endpoints();
+        endpoints.stalled.retain(|&address| address != ep_addr);
+        if stalled {
+            endpoints.stalled.push(ep_addr);
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.0.is_stalled(ep_addr)
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> BusPollResult {
+        if self.0.endpoints().setup.is_empty() {
+            BusPollResult::None
+        } else {
+            BusPollResult::Data {
+                ep_out: 0,
+                ep_in_complete: 0,
+                ep_setup: 1,
+            }
+        }
+    }
+}
+
+/// A class on the mock bus, with the application side of its interchange.
+pub struct Harness<'bus, 'pipe, C, const N: usize = EXTENDED_APDU_LEN> {
+    pub ccid: C,
+    pub host: Host,
+    responder: Option<Responder<'pipe, N>>,
+    device: UsbDevice<'bus, MockBus>,
+}
+
+/// Run `test` against a single slot class constructed with `Ccid::new`.
+pub fn with_ccid(
+    test: impl for<'b, 'p> FnOnce(&mut Harness<'b, 'p, ExtendedApduCcid<'b, 'p, MockBus>>),
+) {
+    let host = Host::default();
+    let allocator = UsbBusAllocator::new(MockBus(host.clone()));
+    let channel = Channel::new();
+    let (requester, responder) = channel.split().unwrap();
+    let ccid = Ccid::new(&allocator, requester, None);
+    let device = UsbDeviceBuilder::new(&allocator, UsbVidPid(0x1209, 0xcc1d)).build();
+    test(&mut Harness {
+        ccid,
+        host,
+        responder: Some(responder),
+        device,
+    });
+}
+
+/// A bus allocator for classes constructed outside of a `Harness`, e.g. to check their errors.
+pub fn allocator() -> UsbBusAllocator<MockBus> {
+    UsbBusAllocator::new(MockBus(Host::default()))
+}
+
+/// Run `test` against the class built by `builder`.
+pub fn with_builder<const N: usize, const MSG: usize>(
+    builder: CcidBuilder<'_>,
+    test: impl for<'b, 'p> FnOnce(&mut Harness<'b, 'p, Ccid<'b, 'p, MockBus, N, MSG>, N>),
+) {
+    let host = Host::default();
+    let allocator = UsbBusAllocator::new(MockBus(host.clone()));
+    let channel = Channel::new();
+    let (requester, responder) = channel.split().unwrap();
+    let ccid = builder.build(&allocator, requester).unwrap();
+    let device = UsbDeviceBuilder::new(&allocator, UsbVidPid(0x1209, 0xcc1d)).build();
+    test(&mut Harness {
+        ccid,
+        host,
+        responder: Some(responder),
+        device,
+    });
+}
+
+impl<'bus, 'pipe, C: UsbClass<MockBus>, const N: usize> Harness<'bus, 'pipe, C, N> {
+    /// Hand a packet to the class as if the host had sent it.
+    pub fn send(&mut self, packet: &[u8]) {
+        self.host.endpoints().out.push_back(packet.to_vec());
+        self.ccid.endpoint_out(self.host.bulk_out());
+    }
+
+    /// Send a message in as many packets as needed.
+    pub fn send_message(&mut self, message: &[u8]) {
+        for packet in message.chunks(PACKET_SIZE) {
+            self.send(packet);
+        }
+    }
+
+    /// Send a message and fetch everything the class sends back.
+    pub fn exchange(&mut self, message: &[u8]) -> Vec<Vec<u8>> {
+        self.send_message(message);
+        self.receive()
+    }
+
+    /// The packets written to the bulk IN endpoint since the last call.
+    pub fn take_written(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.host.endpoints().written)
+    }
+
+    /// Signal that the host fetched the last packet written to the bulk IN endpoint.
+    pub fn complete(&mut self) {
+        self.host.endpoints().in_busy = false;
+        self.ccid.endpoint_in_complete(self.host.bulk_in());
+    }
+
+    /// Fetch packets from the bulk IN endpoint until the class has nothing more to send.
+    pub fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        loop {
+            let written = self.take_written();
+            if written.is_empty() {
+                return packets;
+            }
+            packets.extend(written);
+            self.complete();
+        }
+    }
+
+    /// Fetch a response sent in chained DataBlocks, pulling the blocks after the first with
+    /// XfrBlocks following `seq`.  Returns the abData of all blocks and the last bSeq.
+    pub fn receive_chain(&mut self, mut seq: u8) -> (Vec<u8>, u8) {
+        let mut data = Vec::new();
+        let mut blocks = self.receive();
+        loop {
+            // full blocks are followed by a ZLP
+            blocks.retain(|block| !block.is_empty());
+            let block = blocks.remove(0);
+            data.extend_from_slice(&block[HEADER_LEN..]);
+            // BeginsAndEnds or Ends
+            if matches!(block[9], 0x00 | 0x02) {
+                return (data, seq);
+            }
+            seq = seq.wrapping_add(1);
+            blocks = self.exchange(&xfr_block(seq, 0x10, &[]));
+        }
+    }
+
+    /// The notifications written to the interrupt endpoint since the last call, each
+    /// fetched by the host.
+    pub fn take_notifications(&mut self) -> Vec<Vec<u8>> {
+        let notifications = std::mem::take(&mut self.host.endpoints().notifications);
+        if !notifications.is_empty() {
+            self.host.endpoints().interrupt_busy = false;
+            if let Some(interrupt) = self.host.interrupt() {
+                self.ccid.endpoint_in_complete(interrupt);
+            }
+        }
+        notifications
+    }
+
+    /// Let the device handle a control OUT request without data stage.
+    pub fn control_out(&mut self, request_type: u8, request: u8, value: u16, index: u16) {
+        let mut setup = [request_type, request, 0, 0, 0, 0, 0, 0];
+        setup[2..4].copy_from_slice(&value.to_le_bytes());
+        setup[4..6].copy_from_slice(&index.to_le_bytes());
+        self.host.endpoints().setup.push_back(setup);
+        self.device.poll(&mut [&mut self.ccid]);
+    }
+
+    /// Run the class `poll`, as the device does on every bus event.
+    pub fn poll(&mut self) {
+        self.ccid.poll();
+    }
+
+    pub fn responder(&mut self) -> &mut Responder<'pipe, N> {
+        self.responder.as_mut().unwrap()
+    }
+
+    /// Hand the application side of the interchange to another responder.
+    pub fn take_responder(&mut self) -> Responder<'pipe, N> {
+        self.responder.take().unwrap()
+    }
+
+    /// The command APDU forwarded to the application, if any.
+    pub fn take_request(&mut self) -> Option<Vec<u8>> {
+        self.responder()
+            .take_request()
+            .map(|request| request.to_vec())
+    }
+
+    /// Answer the pending command APDU over the interchange.
+    pub fn respond(&mut self, response: &[u8]) {
+        self.responder()
+            .respond(Data::from_slice(response).unwrap())
+            .unwrap();
+    }
+
+    /// Answer the pending command APDU and let the class pick up the response.
+    pub fn answer(&mut self, response: &[u8]) {
+        self.respond(response);
+        self.poll();
+    }
+}
+
+/// A bulk OUT message with the common header.
+pub fn message(command: u8, slot: u8, seq: u8, level_parameter: u16, data: &[u8]) -> Vec<u8> {
+    let mut message = vec![command];
+    message.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    message.extend_from_slice(&[slot, seq, 0]);
+    message.extend_from_slice(&level_parameter.to_le_bytes());
+    message.extend_from_slice(data);
+    message
+}
+
+pub fn xfr_block(seq: u8, level_parameter: u16, data: &[u8]) -> Vec<u8> {
+    message(0x6f, 0, seq, level_parameter, data)
+}
+
+pub fn power_on(seq: u8) -> Vec<u8> {
+    message(0x62, 0, seq, 0, &[])
+}
+
+/// The message type, bStatus and bError of each response.
+pub fn status(responses: &[Vec<u8>]) -> Vec<(u8, u8, u8)> {
+    responses
+        .iter()
+        .map(|response| (response[0], response[7], response[8]))
+        .collect()
+}
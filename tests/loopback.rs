@@ -0,0 +1,27 @@
+#![cfg(feature = "loopback")]
+
+mod common;
+
+use common::{power_on, status, with_ccid, xfr_block, HEADER_LEN};
+use usbd_ccid::LoopbackResponder;
+
+#[test]
+fn echo() {
+    with_ccid(|t| {
+        let mut app = LoopbackResponder::new(t.take_responder());
+        assert!(!app.poll());
+
+        let responses = t.exchange(&power_on(0));
+        assert_eq!(status(&responses), [(0x80, 0, 0)]);
+
+        let apdu = [0x00, 0xa4, 0x04, 0x00, 0x02, 0x3f, 0x00];
+        t.send(&xfr_block(1, 0, &apdu));
+        assert!(app.poll());
+        t.poll();
+        let responses = t.receive();
+        assert_eq!(status(&responses), [(0x80, 0, 0)]);
+        assert_eq!(responses[0][6], 1);
+        assert_eq!(&responses[0][HEADER_LEN..][..apdu.len()], apdu);
+        assert_eq!(&responses[0][HEADER_LEN + apdu.len()..], [0x90, 0x00]);
+    });
+}
@@ -0,0 +1,193 @@
+mod common;
+
+use common::{message, xfr_block, HEADER_LEN, PACKET_SIZE};
+use usbd_ccid::packet::{
+    self, Chain, ChainedPacket as _, Command, DataBlock, Error, Packet as _, PacketWithData as _,
+    RawPacket, RawPacketExt as _,
+};
+use usbd_ccid::IccStatus;
+
+#[test]
+fn parse_xfr_block() {
+    let message = xfr_block(7, 1, &[0x00, 0xa4, 0x04, 0x00]);
+    let Ok(Command::XfrBlock(block)) = packet::parse_command::<64>(&message) else {
+        panic!("not parsed as XfrBlock");
+    };
+    assert_eq!(block.seq(), 7);
+    assert_eq!(block.slot(), 0);
+    assert!(matches!(block.chain(), Ok(Chain::Begins)));
+    assert_eq!(block.data(), &[0x00, 0xa4, 0x04, 0x00]);
+}
+
+#[test]
+fn parse_rejects_invalid_messages() {
+    assert!(matches!(
+        packet::parse_command::<64>(&[0x6f, 0, 0, 0]),
+        Err(Error::ShortPacket)
+    ));
+    assert!(matches!(
+        packet::parse_command::<64>(&[0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        Err(Error::UnknownCommand(0x01))
+    ));
+    assert!(matches!(
+        packet::parse_command::<16>(&xfr_block(0, 0, &[0; 7])),
+        Err(Error::MessageTooLong)
+    ));
+}
+
+#[test]
+fn parse_clamps_data_to_received_bytes() {
+    // dwLength announces 100 bytes, only 3 are present
+    let mut message = xfr_block(0, 0, &[1, 2, 3]);
+    message[1..5].copy_from_slice(&100u32.to_le_bytes());
+    let Ok(Command::XfrBlock(block)) = packet::parse_command::<64>(&message) else {
+        panic!("not parsed as XfrBlock");
+    };
+    assert_eq!(block.data(), &[1, 2, 3]);
+    let command = Command::XfrBlock(block);
+    assert!(!format!("{command:?}").is_empty());
+}
+
+#[test]
+fn data_block() {
+    let block = DataBlock::new(1, 4, IccStatus::Active, Chain::Ends, &[0x90, 0x00]);
+    assert_eq!(block.slot(), 1);
+    assert_eq!(block.seq(), 4);
+    assert_eq!(block.icc_status(), IccStatus::Active);
+    assert_eq!(block.chain(), Chain::Ends);
+    assert_eq!(block.data(), [0x90, 0x00]);
+    assert_eq!(
+        &RawPacket::from(block)[..],
+        [0x80, 2, 0, 0, 0, 1, 4, 0, 0, 0x02, 0x90, 0x00]
+    );
+}
+
+#[test]
+fn response_pull() {
+    let classify = |level_parameter, data: &[u8]| {
+        let message = xfr_block(0, level_parameter, data);
+        let Ok(Command::XfrBlock(block)) = packet::parse_command::<64>(&message) else {
+            panic!("not parsed as XfrBlock");
+        };
+        block.is_response_pull()
+    };
+    assert!(classify(0x10, &[]));
+    // blocks of a command
+    for level_parameter in [0x00, 0x01, 0x02, 0x03] {
+        assert!(!classify(level_parameter, &[0x00, 0xca, 0x00, 0x00]));
+    }
+    // an unknown level parameter is not a pull
+    assert!(!classify(0x11, &[]));
+}
+
+#[test]
+fn parse_set_parameters() {
+    let mut message = message(0x61, 0, 3, 0, &[0x11, 0x10, 0x00, 0x4d, 0x00, 0xfe, 0x00]);
+    // bProtocolNum: T=1
+    message[7] = 1;
+    let Ok(Command::SetParameters(command)) = packet::parse_command::<64>(&message) else {
+        panic!("not parsed as SetParameters");
+    };
+    assert_eq!(command.seq(), 3);
+    assert_eq!(command.protocol(), 1);
+    assert_eq!(
+        command.protocol_data(),
+        [0x11, 0x10, 0x00, 0x4d, 0x00, 0xfe, 0x00]
+    );
+}
+
+#[test]
+fn data_len_of_short_packet() {
+    let packet = RawPacket::from_slice(&[0x6f, 0x04, 0x00]).unwrap();
+    assert_eq!(packet.data_len(), None);
+    let packet = RawPacket::from_slice(&xfr_block(0, 0, &[1, 2, 3])).unwrap();
+    assert_eq!(packet.data_len(), Some(3));
+}
+
+#[test]
+fn parse_mechanical() {
+    let mut message = message(0x71, 0, 1, 0, &[]);
+    // bFunction: eject
+    message[7] = 2;
+    let command = packet::parse_command::<64>(&message).unwrap();
+    assert_eq!(command.command_type() as u8, 0x71);
+    let Command::Mechanical(command) = command else {
+        panic!("not parsed as Mechanical");
+    };
+    assert_eq!(command.function(), 2);
+}
+
+#[test]
+fn parse_set_data_rate_and_clock_frequency() {
+    let message = message(0x73, 0, 2, 0, &[0xfc, 0x0d, 0, 0, 0x80, 0x25, 0, 0]);
+    let Ok(Command::SetDataRateAndClockFrequency(command)) = packet::parse_command::<64>(&message)
+    else {
+        panic!("not parsed as SetDataRateAndClockFrequency");
+    };
+    assert_eq!(command.seq(), 2);
+    assert_eq!(command.data(), [0xfc, 0x0d, 0, 0, 0x80, 0x25, 0, 0]);
+}
+
+#[test]
+fn parse_t0_apdu() {
+    let mut message = message(0x6a, 0, 0, 0, &[]);
+    // bmChanges: both classes, bClassGetResponse echoes the class, bClassEnvelope 0x80
+    message[7..10].copy_from_slice(&[0x03, 0xff, 0x80]);
+    let Ok(Command::T0Apdu(command)) = packet::parse_command::<64>(&message) else {
+        panic!("not parsed as T0Apdu");
+    };
+    assert_eq!(command.changes(), 0x03);
+    assert_eq!(command.class_get_response(), 0xff);
+    assert_eq!(command.class_envelope(), 0x80);
+}
+
+#[test]
+fn response_blocks_empty() {
+    let blocks: Vec<_> = packet::response_blocks(0, 3, IccStatus::Active, &[]).collect();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].chain(), Chain::BeginsAndEnds);
+    assert_eq!(blocks[0].seq(), 3);
+    assert!(blocks[0].data().is_empty());
+}
+
+#[test]
+fn response_blocks_single() {
+    let data = [0x90, 0x00];
+    let mut blocks = packet::response_blocks(1, 9, IccStatus::Active, &data);
+    let block = blocks.next().unwrap();
+    assert!(blocks.next().is_none());
+    assert_eq!(block.chain(), Chain::BeginsAndEnds);
+    assert_eq!(
+        &RawPacket::from(block)[..],
+        [0x80, 2, 0, 0, 0, 1, 9, 0, 0, 0, 0x90, 0x00]
+    );
+}
+
+#[test]
+fn response_blocks_reassemble() {
+    let chunk_len = PACKET_SIZE - HEADER_LEN;
+    let data: Vec<u8> = (0..3 * chunk_len + 5).map(|i| i as u8).collect();
+    let blocks: Vec<_> = packet::response_blocks(0, 0xff, IccStatus::Active, &data).collect();
+
+    let chains: Vec<_> = blocks.iter().map(|block| block.chain()).collect();
+    assert_eq!(
+        chains,
+        [
+            Chain::Begins,
+            Chain::Continues,
+            Chain::Continues,
+            Chain::Ends
+        ]
+    );
+    // the sequence numbers of the XfrBlocks pulling the blocks, wrapping
+    let seqs: Vec<_> = blocks.iter().map(|block| block.seq()).collect();
+    assert_eq!(seqs, [0xff, 0, 1, 2]);
+    assert!(blocks.iter().all(|block| block.data().len() <= chunk_len));
+
+    let reassembled: Vec<u8> = blocks
+        .iter()
+        .flat_map(|block| block.data())
+        .copied()
+        .collect();
+    assert_eq!(reassembled, data);
+}
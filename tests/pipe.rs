@@ -0,0 +1,917 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::{
+    message, power_on, status, with_builder, with_ccid, xfr_block, HEADER_LEN, PACKET_SIZE,
+};
+use usb_device::{UsbDirection, UsbError};
+use usbd_ccid::{
+    CcidBuilder, CcidDescriptor, PacketTap, PollResult, ResetReason, SeqPolicy, State, Status,
+    SHORT_APDU_LEN, SHORT_MSG_LENGTH,
+};
+
+#[test]
+fn short_transfer() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xa4, 0x04, 0x00]));
+        assert!(t.take_written().is_empty());
+        assert_eq!(t.ccid.state(), State::Processing);
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xa4, 0x04, 0x00]);
+
+        t.respond(&[0x90, 0x00]);
+        assert_eq!(t.ccid.poll_with_result(), PollResult::ResponseReady);
+        assert_eq!(
+            t.take_written(),
+            [vec![0x80, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0x90, 0x00]]
+        );
+        assert_eq!(t.ccid.state(), State::Idle);
+    });
+}
+
+#[test]
+fn chained_command() {
+    with_ccid(|t| {
+        // the reader asks for the next block
+        assert_eq!(
+            t.exchange(&xfr_block(0, 0x01, &[1, 2, 3])),
+            [vec![0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10]]
+        );
+        assert_eq!(t.ccid.state(), State::Receiving);
+        assert!(t.take_request().is_none());
+
+        assert_eq!(
+            t.exchange(&xfr_block(1, 0x03, &[4])),
+            [vec![0x80, 0, 0, 0, 0, 0, 1, 0, 0, 0x10]]
+        );
+
+        assert!(t.exchange(&xfr_block(2, 0x02, &[5, 6])).is_empty());
+        assert_eq!(t.take_request().unwrap(), [1, 2, 3, 4, 5, 6]);
+    });
+}
+
+#[test]
+fn chained_response() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xb0, 0x00, 0x00, 0x00]));
+        t.take_request().unwrap();
+        let data: Vec<u8> = (0..2 * (PACKET_SIZE - HEADER_LEN) + 3)
+            .map(|i| i as u8)
+            .collect();
+        t.answer(&data);
+
+        // full packets are terminated with a ZLP
+        let mut blocks = t.receive();
+        assert_eq!(blocks.pop(), Some(Vec::new()));
+        for seq in 1..=2 {
+            let received = t.exchange(&xfr_block(seq, 0x10, &[]));
+            blocks.extend(received.into_iter().filter(|block| !block.is_empty()));
+        }
+
+        let chains: Vec<_> = blocks.iter().map(|block| block[9]).collect();
+        assert_eq!(chains, [0x01, 0x03, 0x02]);
+        let seqs: Vec<_> = blocks.iter().map(|block| block[6]).collect();
+        assert_eq!(seqs, [0, 1, 2]);
+        let reassembled: Vec<u8> = blocks
+            .iter()
+            .flat_map(|block| &block[HEADER_LEN..])
+            .copied()
+            .collect();
+        assert_eq!(reassembled, data);
+        assert_eq!(t.ccid.state(), State::Idle);
+    });
+}
+
+#[test]
+fn long_message() {
+    with_ccid(|t| {
+        let data: Vec<u8> = (0..PACKET_SIZE + 20).map(|i| i as u8).collect();
+        let message = xfr_block(0, 0, &data);
+        t.send(&message[..PACKET_SIZE]);
+        assert!(t.take_request().is_none());
+
+        t.send(&message[PACKET_SIZE..]);
+        assert_eq!(t.take_request().unwrap(), data);
+    });
+}
+
+#[test]
+fn full_packet_response_ends_with_zlp() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        let data = vec![0x42; PACKET_SIZE - HEADER_LEN];
+        t.answer(&data);
+
+        let written = t.take_written();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].len(), PACKET_SIZE);
+        assert_eq!(&written[0][HEADER_LEN..], &data[..]);
+
+        t.complete();
+        assert_eq!(t.take_written(), [Vec::<u8>::new()]);
+        t.complete();
+        assert!(t.take_written().is_empty());
+    });
+}
+
+#[test]
+fn abort_while_receiving() {
+    with_ccid(|t| {
+        t.exchange(&xfr_block(0, 0x01, &[1, 2, 3]));
+        assert_eq!(t.ccid.state(), State::Receiving);
+
+        t.send(&message(0x72, 0, 1, 0, &[]));
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert!(t.take_request().is_none());
+
+        // the partial command is gone, the next one starts afresh
+        t.send(&xfr_block(2, 0, &[9]));
+        assert_eq!(t.take_request().unwrap(), [9]);
+    });
+}
+
+#[test]
+fn blocked_write_is_retried() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        // refused by the write in `poll_app` and the retry in `flush_outbox`
+        t.host.block_writes(2);
+        t.answer(&[0x90, 0x00]);
+        assert!(t.take_written().is_empty());
+
+        t.poll();
+        assert_eq!(
+            t.take_written(),
+            [vec![0x80, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0x90, 0x00]]
+        );
+    });
+}
+
+#[test]
+fn write_blocked_once_is_flushed_by_the_same_poll() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.host.block_writes(1);
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(
+            t.take_written(),
+            [vec![0x80, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0x90, 0x00]]
+        );
+        assert_eq!(t.ccid.state(), State::Idle);
+    });
+}
+
+#[test]
+fn packet_tap_sees_both_directions() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let tap_log = log.clone();
+    let tap: &'static mut PacketTap = Box::leak(Box::new(move |direction, packet: &[u8]| {
+        tap_log.lock().unwrap().push((direction, packet.to_vec()))
+    }));
+    with_ccid(|t| {
+        t.ccid.set_packet_tap(Some(tap));
+        let atr = t.exchange(&power_on(0)).remove(0);
+        assert_eq!(atr[0], 0x80);
+
+        let log = log.lock().unwrap();
+        assert_eq!(
+            *log,
+            [(UsbDirection::Out, power_on(0)), (UsbDirection::In, atr)]
+        );
+    });
+}
+
+#[test]
+fn keep_alive_while_processing() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0x2a, 0x9e, 0x9a]));
+        t.take_request().unwrap();
+
+        // an empty XfrBlock expecting more data is answered with a time extension
+        let responses = t.exchange(&xfr_block(0, 0x10, &[]));
+        assert_eq!(responses, [vec![0x80, 0, 0, 0, 0, 0, 0, 0x80, 1, 0]]);
+        assert_eq!(t.ccid.state(), State::Processing);
+        assert_eq!(t.ccid.reset_count(), 0);
+
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(
+            t.receive(),
+            [vec![0x80, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0x90, 0x00]]
+        );
+    });
+}
+
+#[test]
+fn chain_declaring_too_long_a_command() {
+    with_ccid(|t| {
+        // extended Lc of 4096 bytes, more than the interchange holds
+        let header = [0x00, 0xdb, 0x3f, 0xff, 0x00, 0x10, 0x00, 0x5c];
+        assert_eq!(
+            t.exchange(&xfr_block(0, 0x01, &header)),
+            [vec![0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10]]
+        );
+        assert!(t.take_request().is_none());
+
+        // the rest of the chain is swallowed, and the command rejected once it ends
+        assert_eq!(
+            t.exchange(&xfr_block(1, 0x03, &[0; 40])),
+            [vec![0x80, 0, 0, 0, 0, 0, 1, 0, 0, 0x10]]
+        );
+        assert_eq!(
+            t.exchange(&xfr_block(2, 0x02, &[0; 40])),
+            [vec![0x80, 2, 0, 0, 0, 0, 2, 0, 0, 0, 0x67, 0x00]]
+        );
+        assert!(t.take_request().is_none());
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert_eq!(t.ccid.reset_count(), 0);
+
+        t.send(&xfr_block(3, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn chain_overflowing_the_interchange() {
+    with_builder::<SHORT_APDU_LEN, 1024>(CcidBuilder::new(), |t| {
+        // no Lc, so the overflow is only noticed with the second block
+        let data = [0x42; 200];
+        let responses = t.exchange(&xfr_block(0, 0x01, &data));
+        assert_eq!(status(&responses), [(0x80, 0, 0)]);
+        for seq in 1..=2 {
+            let responses = t.exchange(&xfr_block(seq, 0x03, &data));
+            assert_eq!(responses[0][9], 0x10);
+        }
+        let responses = t.exchange(&xfr_block(3, 0x02, &data));
+        assert_eq!(&responses[0][HEADER_LEN..], [0x67, 0x00]);
+        assert!(t.take_request().is_none());
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert_eq!(t.ccid.reset_count(), 0);
+    });
+}
+
+#[test]
+fn no_wait_extension_after_the_response() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0x2a, 0x9e, 0x9a]));
+        t.take_request().unwrap();
+        assert!(matches!(
+            t.ccid.send_wait_extension(),
+            Status::ReceivedData(_)
+        ));
+        assert_eq!(t.receive(), [vec![0x80, 0, 0, 0, 0, 0, 0, 0x80, 1, 0]]);
+
+        // the response arrives between two polls of the firmware
+        t.respond(&[0x90, 0x00]);
+        assert!(matches!(t.ccid.send_wait_extension(), Status::Idle));
+        assert_eq!(
+            t.receive(),
+            [vec![0x80, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0x90, 0x00]]
+        );
+        assert!(matches!(t.ccid.send_wait_extension(), Status::Idle));
+        assert!(t.receive().is_empty());
+    });
+}
+
+#[test]
+fn wait_extension_multiplier() {
+    let builder = CcidBuilder::new().wait_extension_multiplier(4);
+    with_builder::<SHORT_APDU_LEN, 1024>(builder, |t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0x47, 0x80, 0x00]));
+        t.take_request().unwrap();
+        t.ccid.send_wait_extension();
+        assert_eq!(t.receive(), [vec![0x80, 0, 0, 0, 0, 0, 0, 0x80, 4, 0]]);
+        // the next one is due once the granted block waiting times elapsed
+        for _ in 0..3 {
+            t.ccid.send_wait_extension();
+        }
+        assert!(t.receive().is_empty());
+
+        // clamped to 1..=16
+        t.ccid.set_wait_extension_multiplier(100);
+        t.ccid.send_wait_extension();
+        assert_eq!(t.receive(), [vec![0x80, 0, 0, 0, 0, 0, 0, 0x80, 16, 0]]);
+        for _ in 0..15 {
+            t.ccid.send_wait_extension();
+        }
+        t.ccid.set_wait_extension_multiplier(0);
+        t.ccid.send_wait_extension();
+        assert_eq!(t.receive(), [vec![0x80, 0, 0, 0, 0, 0, 0, 0x80, 1, 0]]);
+    });
+}
+
+#[test]
+fn granted_block_waiting_time() {
+    with_ccid(|t| {
+        // bBWI: the host granted 3 block waiting times
+        let mut command = xfr_block(0, 0, &[0x00, 0x47, 0x80, 0x00]);
+        command[7] = 3;
+        t.send(&command);
+        t.take_request().unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                t.ccid.send_wait_extension(),
+                Status::ReceivedData(_)
+            ));
+            assert!(t.receive().is_empty());
+        }
+        // requesting at least as much time as granted before
+        t.ccid.send_wait_extension();
+        assert_eq!(t.receive(), [vec![0x80, 0, 0, 0, 0, 0, 0, 0x80, 3, 0]]);
+        assert_eq!(t.ccid.stats().wait_extensions, 1);
+
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(status(&t.receive()), [(0x80, 0, 0)]);
+    });
+}
+
+#[test]
+fn two_packets_primed_both_sent_in_order() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0x2a, 0x9e, 0x9a]));
+        t.take_request().unwrap();
+        t.host.block_writes(3);
+        assert!(matches!(
+            t.ccid.send_wait_extension(),
+            Status::ReceivedData(_)
+        ));
+        t.answer(&[0x90, 0x00]);
+        assert!(t.take_written().is_empty());
+
+        t.poll();
+        assert_eq!(
+            t.receive(),
+            [
+                vec![0x80, 0, 0, 0, 0, 0, 0, 0x80, 1, 0],
+                vec![0x80, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0x90, 0x00],
+            ]
+        );
+        assert_eq!(t.ccid.state(), State::Idle);
+    });
+}
+
+#[test]
+fn continuation_larger_than_expected() {
+    with_ccid(|t| {
+        let data: Vec<u8> = (0..PACKET_SIZE + 5).map(|i| i as u8).collect();
+        let message = xfr_block(0, 0, &data);
+        t.send(&message[..PACKET_SIZE]);
+        // 10 bytes are declared, 30 arrive
+        let mut rest = message[PACKET_SIZE..].to_vec();
+        rest.extend_from_slice(&[0xee; 20]);
+        t.send(&rest);
+
+        assert_eq!(t.take_request().unwrap(), data);
+        assert_eq!(t.ccid.reset_count(), 0);
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(status(&t.receive()), [(0x80, 0, 0)]);
+    });
+}
+
+#[test]
+fn final_continuation_larger_than_expected() {
+    with_ccid(|t| {
+        let data: Vec<u8> = (0..2 * PACKET_SIZE).map(|i| i as u8).collect();
+        let message = xfr_block(0, 0, &data);
+        t.send(&message[..PACKET_SIZE]);
+        t.send(&message[PACKET_SIZE..2 * PACKET_SIZE]);
+        // the bytes beyond dwLength look like another command, they are dropped
+        let mut rest = message[2 * PACKET_SIZE..].to_vec();
+        rest.extend_from_slice(&xfr_block(1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.send(&rest);
+
+        assert_eq!(t.take_request().unwrap(), data);
+        assert!(t.take_written().is_empty());
+        assert_eq!(t.ccid.reset_count(), 0);
+        assert_eq!(t.ccid.state(), State::Processing);
+    });
+}
+
+#[test]
+fn short_apdu_build_rejects_long_messages() {
+    with_builder::<SHORT_APDU_LEN, SHORT_MSG_LENGTH>(CcidBuilder::new(), |t| {
+        let descriptor = CcidDescriptor::parse(&t.ccid.functional_descriptor()).unwrap();
+        assert_eq!(descriptor.max_message_length as usize, SHORT_MSG_LENGTH);
+
+        // the message is swallowed, and rejected once complete
+        let responses = t.exchange(&xfr_block(0, 0, &[0x42; SHORT_MSG_LENGTH]));
+        assert_eq!(status(&responses), [(0x80, 0x40, 0xff)]);
+        assert!(t.take_request().is_none());
+        assert_eq!(t.ccid.state(), State::Idle);
+
+        t.send(&xfr_block(1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn clear_halt_while_receiving_long_message() {
+    with_ccid(|t| {
+        let data = vec![0x42; PACKET_SIZE + 20];
+        t.send(&xfr_block(0, 0, &data)[..PACKET_SIZE]);
+        assert!(t.take_request().is_none());
+
+        // CLEAR_FEATURE(ENDPOINT_HALT) on the bulk OUT endpoint
+        let address = u8::from(t.host.bulk_out()) as u16;
+        t.control_out(0x02, 0x01, 0, address);
+
+        // the next packet starts a new message instead of continuing the old one
+        t.send(&xfr_block(1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(status(&t.receive()), [(0x80, 0, 0)]);
+    });
+}
+
+#[test]
+fn reset_reasons() {
+    with_ccid(|t| {
+        assert_eq!(t.ccid.reset_count(), 0);
+        assert_eq!(t.ccid.take_last_reset_reason(), None);
+
+        // wLevelParameter 0x05 is not a chain parameter
+        t.send(&xfr_block(0, 0x05, &[1]));
+        assert_eq!(t.ccid.reset_count(), 1);
+        // a continuation without a chain
+        t.send(&xfr_block(1, 0x03, &[1]));
+        assert_eq!(t.ccid.reset_count(), 2);
+        // only the most recent reason is kept, and taken once
+        assert_eq!(
+            t.ccid.take_last_reset_reason(),
+            Some(ResetReason::UnexpectedCommand)
+        );
+        assert_eq!(t.ccid.take_last_reset_reason(), None);
+
+        t.send(&xfr_block(2, 0x05, &[1]));
+        assert_eq!(
+            t.ccid.take_last_reset_reason(),
+            Some(ResetReason::UnknownChain)
+        );
+        assert_eq!(t.ccid.reset_count(), 3);
+    });
+}
+
+#[test]
+fn short_packet() {
+    with_ccid(|t| {
+        t.send(&[0x6f, 0x04, 0x00]);
+        assert_eq!(
+            t.ccid.take_last_reset_reason(),
+            Some(ResetReason::ShortPacket)
+        );
+        assert!(t.take_written().is_empty());
+
+        t.send(&xfr_block(0, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn commands_are_routed_by_slot() {
+    with_ccid(|t| {
+        t.ccid.set_num_slots(2).unwrap();
+
+        let responses = t.exchange(&message(0x62, 1, 0, 0, &[]));
+        assert_eq!(status(&responses), [(0x80, 0, 0)]);
+        assert_eq!(responses[0][5], 1);
+
+        t.send(&message(0x6f, 1, 1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(
+            t.receive(),
+            [vec![0x80, 2, 0, 0, 0, 1, 1, 0, 0, 0, 0x90, 0x00]]
+        );
+
+        // slot 0 keeps working
+        t.send(&message(0x6f, 0, 2, 0, &[0x00, 0xcb, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xcb, 0x00, 0x00]);
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(
+            t.receive(),
+            [vec![0x80, 2, 0, 0, 0, 0, 2, 0, 0, 0, 0x90, 0x00]]
+        );
+
+        // slot 2 does not exist
+        let responses = t.exchange(&message(0x6f, 2, 3, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(status(&responses), [(0x80, 0x42, 0x05)]);
+        assert_eq!(responses[0][5], 2);
+        assert!(t.take_request().is_none());
+    });
+}
+
+// The ABORT handshake: the class request, then PC_to_RDR_Abort over the bulk pipe.
+fn abort<C: usb_device::class::UsbClass<common::MockBus>>(
+    t: &mut common::Harness<'_, '_, C>,
+    seq: u8,
+) -> Vec<Vec<u8>> {
+    t.control_out(0x21, 0x01, (seq as u16) << 8, 0);
+    t.exchange(&message(0x72, 0, seq, 0, &[]))
+}
+
+#[test]
+fn abort_in_every_state() {
+    with_ccid(|t| {
+        // Receiving
+        t.exchange(&xfr_block(0, 0x01, &[1, 2, 3]));
+        assert_eq!(status(&abort(t, 1)), [(0x81, 0, 0)]);
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert!(t.take_request().is_none());
+
+        // Processing: the application is told to drop the command
+        t.send(&xfr_block(2, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        assert_eq!(status(&abort(t, 3)), [(0x81, 0, 0)]);
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert!(t.responder().is_canceled());
+        t.responder().acknowledge_cancel().unwrap();
+
+        // Sending: the rest of the response is dropped
+        t.send(&xfr_block(4, 0, &[0x00, 0xb0, 0x00, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.answer(&[0x42; 2 * PACKET_SIZE]);
+        t.receive();
+        assert_eq!(t.ccid.state(), State::Sending);
+        assert_eq!(status(&abort(t, 5)), [(0x81, 0, 0)]);
+        assert_eq!(t.ccid.state(), State::Idle);
+
+        t.send(&xfr_block(6, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+        assert_eq!(t.ccid.reset_count(), 0);
+    });
+}
+
+#[test]
+fn full_outbox_keeps_queued_packets() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0x2a, 0x9e, 0x9a]));
+        t.take_request().unwrap();
+        t.host.block_writes(usize::MAX);
+        for _ in 0..3 {
+            t.ccid.send_wait_extension();
+        }
+        assert_eq!(t.ccid.stats().dropped_packets, 1);
+        assert_eq!(t.ccid.state(), State::Processing);
+        assert_eq!(t.ccid.reset_count(), 0);
+
+        t.host.block_writes(0);
+        t.poll();
+        assert_eq!(t.receive().len(), 2);
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(
+            t.receive(),
+            [vec![0x80, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0x90, 0x00]]
+        );
+    });
+}
+
+#[test]
+fn streamed_response() {
+    with_ccid(|t| {
+        t.ccid.set_streaming(true);
+        t.send(&xfr_block(0, 0, &[0x00, 0xb0, 0x00, 0x00, 0x00]));
+        t.take_request().unwrap();
+        let data: Vec<u8> = (0..3 * PACKET_SIZE).map(|i| i as u8).collect();
+
+        // nothing is sent before it is known whether more blocks follow
+        let block = PACKET_SIZE - HEADER_LEN;
+        assert_eq!(t.ccid.stream_response(&data[..block], false), block);
+        t.poll();
+        assert!(t.receive().is_empty());
+        assert_eq!(t.ccid.state(), State::Processing);
+
+        // the buffer holds two blocks, the application pushes the rest as the host fetches them
+        let mut pushed = block + t.ccid.stream_response(&data[block..], true);
+        assert!(pushed < data.len());
+        t.poll();
+        let mut blocks = t.receive();
+        let mut received = Vec::new();
+        let mut seq = 0;
+        loop {
+            let block = blocks.remove(0);
+            received.extend_from_slice(&block[HEADER_LEN..]);
+            if block[9] == 0x02 {
+                break;
+            }
+            if pushed < data.len() {
+                pushed += t.ccid.stream_response(&data[pushed..], true);
+            }
+            seq += 1;
+            blocks = t.exchange(&xfr_block(seq, 0x10, &[]));
+            blocks.retain(|block| !block.is_empty());
+        }
+        assert_eq!(received, data);
+        assert_eq!(t.ccid.state(), State::Idle);
+
+        // the interchange is handed back for the next command
+        t.respond(&[]);
+        t.send(&xfr_block(seq + 1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn chunking_follows_the_packet_size() {
+    assert_eq!(
+        PACKET_SIZE,
+        if cfg!(feature = "highspeed-usb") {
+            512
+        } else {
+            64
+        }
+    );
+    with_ccid(|t| {
+        // a command of three packets
+        let data: Vec<u8> = (0..3 * PACKET_SIZE - HEADER_LEN).map(|i| i as u8).collect();
+        let message = xfr_block(0, 0, &data);
+        for packet in message.chunks(PACKET_SIZE) {
+            assert_eq!(packet.len(), PACKET_SIZE);
+            t.send(packet);
+        }
+        assert_eq!(t.take_request().unwrap(), data);
+
+        // a response of one full block and a short one, carrying the last HEADER_LEN bytes
+        let response: Vec<u8> = (0..PACKET_SIZE).map(|i| i as u8).collect();
+        t.answer(&response);
+        let mut blocks = t.receive();
+        blocks.extend(t.exchange(&xfr_block(1, 0x10, &[])));
+        let sizes: Vec<_> = blocks.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [PACKET_SIZE, 0, 2 * HEADER_LEN]);
+    });
+}
+
+#[test]
+fn sequence_mismatch_within_a_chain() {
+    with_ccid(|t| {
+        // tolerated by default
+        t.exchange(&xfr_block(0, 0x01, &[1, 2, 3]));
+        assert!(t.exchange(&xfr_block(5, 0x02, &[4])).is_empty());
+        assert_eq!(t.take_request().unwrap(), [1, 2, 3, 4]);
+        t.answer(&[0x90, 0x00]);
+        t.receive();
+
+        t.ccid.set_seq_policy(SeqPolicy::Strict);
+        t.exchange(&xfr_block(6, 0x01, &[1, 2, 3]));
+        let responses = t.exchange(&xfr_block(9, 0x02, &[4]));
+        // XfrBlock failed with CmdAborted, echoing the sequence number
+        assert_eq!(status(&responses), [(0x80, 0x40, 0xff)]);
+        assert_eq!(responses[0][6], 9);
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert!(t.take_request().is_none());
+        assert_eq!(t.ccid.reset_count(), 0);
+
+        t.send(&xfr_block(10, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn extended_apdu_round_trip() {
+    with_builder::<SHORT_APDU_LEN, 1024>(CcidBuilder::new(), |t| {
+        // short APDU level exchange
+        assert_eq!(t.ccid.features() & 0x0007_0000, 0x0002_0000);
+    });
+    with_ccid(|t| {
+        // short and extended APDU level exchange
+        assert_eq!(t.ccid.features() & 0x0007_0000, 0x0004_0000);
+
+        // PUT DATA with 1 KB of data: extended Lc
+        let mut apdu = vec![0x00, 0xda, 0x3f, 0xff, 0x00, 0x04, 0x00];
+        apdu.extend((0..1024).map(|i| i as u8));
+        let message = xfr_block(0, 0, &apdu);
+        for packet in message.chunks(PACKET_SIZE) {
+            t.send(packet);
+        }
+        assert_eq!(t.take_request().unwrap(), apdu);
+
+        let mut response: Vec<u8> = (0..1024).map(|i| (i * 7) as u8).collect();
+        response.extend_from_slice(&[0x90, 0x00]);
+        t.answer(&response);
+        assert_eq!(t.receive_chain(0).0, response);
+        assert_eq!(t.ccid.state(), State::Idle);
+    });
+}
+
+#[test]
+fn busy_interchange() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0x47, 0x80, 0x00]));
+        t.take_request().unwrap();
+        // the application has not yet acknowledged the cancelation of the aborted command
+        abort(t, 1);
+        let responses = t.exchange(&xfr_block(2, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(status(&responses), [(0x80, 0x40, 0xe0)]);
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert_eq!(t.ccid.reset_count(), 0);
+
+        // the host retries
+        t.responder().acknowledge_cancel().unwrap();
+        t.send(&xfr_block(3, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn message_longer_than_advertised() {
+    with_ccid(|t| {
+        let descriptor = CcidDescriptor::parse(&t.ccid.functional_descriptor()).unwrap();
+        let len = descriptor.max_message_length as usize + 100;
+        let message = xfr_block(0, 0, &vec![0x42; len - HEADER_LEN]);
+        // the remaining packets are swallowed, the message is rejected once complete
+        let packets: Vec<_> = message.chunks(PACKET_SIZE).collect();
+        let (last, packets) = packets.split_last().unwrap();
+        for packet in packets {
+            t.send(packet);
+        }
+        assert!(t.receive().is_empty());
+        let responses = t.exchange(last);
+        assert_eq!(status(&responses), [(0x80, 0x40, 0xff)]);
+        assert!(t.take_request().is_none());
+        assert_eq!(t.ccid.reset_count(), 0);
+
+        t.send(&xfr_block(1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn write_error_is_surfaced() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.host.fail_writes(true);
+        t.answer(&[0x90, 0x00]);
+        assert!(matches!(
+            t.ccid.take_write_error(),
+            Some(UsbError::InvalidState)
+        ));
+        assert!(t.ccid.take_write_error().is_none());
+        assert_eq!(
+            t.ccid.take_last_reset_reason(),
+            Some(ResetReason::WriteFailed)
+        );
+        assert_eq!(t.ccid.state(), State::Idle);
+
+        // the next transfer goes through once the endpoint recovered
+        t.host.fail_writes(false);
+        t.send(&xfr_block(1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(status(&t.receive()), [(0x80, 0, 0)]);
+    });
+}
+
+#[test]
+fn processing_timeout() {
+    with_ccid(|t| {
+        t.ccid.set_processing_timeout(Some(2));
+        t.send(&xfr_block(0, 0, &[0x00, 0x47, 0x80, 0x00]));
+        t.take_request().unwrap();
+
+        // polls are not counted
+        for _ in 0..10 {
+            t.poll();
+        }
+        assert_eq!(t.ccid.state(), State::Processing);
+        for _ in 0..2 {
+            assert!(matches!(
+                t.ccid.send_wait_extension(),
+                Status::ReceivedData(_)
+            ));
+            assert_eq!(t.receive(), [vec![0x80, 0, 0, 0, 0, 0, 0, 0x80, 1, 0]]);
+        }
+
+        // the application did not respond in time: IccMute
+        assert!(matches!(t.ccid.send_wait_extension(), Status::Idle));
+        assert_eq!(status(&t.receive()), [(0x80, 0x40, 0xfe)]);
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert!(t.responder().is_canceled());
+    });
+}
+
+#[test]
+fn abort_halfway_through_a_response() {
+    with_ccid(|t| {
+        t.send(&xfr_block(0, 0, &[0x00, 0xb0, 0x00, 0x00, 0x00]));
+        t.take_request().unwrap();
+        t.answer(&[0x42; 3 * (PACKET_SIZE - HEADER_LEN)]);
+        t.receive();
+        let blocks = t.exchange(&xfr_block(1, 0x10, &[]));
+        assert_eq!(blocks[0][9], 0x03);
+
+        // only the abort is answered, the last block is never sent
+        let responses = abort(t, 2);
+        assert_eq!(status(&responses), [(0x81, 0, 0)]);
+        assert_eq!(t.ccid.state(), State::Idle);
+        t.poll();
+        assert!(t.receive().is_empty());
+
+        t.send(&xfr_block(3, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn application_cancels() {
+    with_ccid(|t| {
+        // nothing to cancel
+        assert!(!t.ccid.cancel());
+        assert!(t.receive().is_empty());
+
+        t.send(&xfr_block(0, 0, &[0x00, 0x88, 0x00, 0x00]));
+        t.take_request().unwrap();
+        assert!(t.ccid.cancel());
+        assert_eq!(status(&t.receive()), [(0x80, 0x40, 0xff)]);
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert!(t.responder().is_canceled());
+        assert!(!t.ccid.cancel());
+
+        t.responder().acknowledge_cancel().unwrap();
+        t.send(&xfr_block(1, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}
+
+#[test]
+fn begins_followed_by_empty_ends() {
+    with_ccid(|t| {
+        // every block is acknowledged, even if the data turns out to be complete
+        assert_eq!(
+            t.exchange(&xfr_block(0, 0x01, &[0x00, 0xca, 0x00, 0x00])),
+            [vec![0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10]]
+        );
+        // the empty Ends hands the command to the application without another round trip
+        assert!(t.exchange(&xfr_block(1, 0x02, &[])).is_empty());
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+        t.answer(&[0x90, 0x00]);
+        assert_eq!(
+            t.receive(),
+            [vec![0x80, 2, 0, 0, 0, 0, 1, 0, 0, 0, 0x90, 0x00]]
+        );
+    });
+}
+
+#[test]
+fn response_in_progress() {
+    with_ccid(|t| {
+        // Idle
+        assert!(!t.ccid.response_in_progress());
+
+        // Receiving: the acknowledgement stays in the outbox while the endpoint is blocked
+        t.host.block_writes(usize::MAX);
+        t.send(&xfr_block(0, 0x01, &[1, 2, 3]));
+        assert!(t.ccid.response_in_progress());
+        t.host.block_writes(0);
+        t.poll();
+        t.receive();
+        assert!(!t.ccid.response_in_progress());
+
+        // Processing
+        t.send(&xfr_block(1, 0x02, &[4]));
+        t.take_request().unwrap();
+        assert!(!t.ccid.response_in_progress());
+
+        // Sending, with an empty outbox while the host has not pulled the next block
+        t.answer(&[0x42; 2 * PACKET_SIZE]);
+        t.receive();
+        assert_eq!(t.ccid.state(), State::Sending);
+        assert!(t.ccid.response_in_progress());
+
+        for seq in 2..=3 {
+            t.exchange(&xfr_block(seq, 0x10, &[]));
+        }
+        assert_eq!(t.ccid.state(), State::Idle);
+        assert!(!t.ccid.response_in_progress());
+    });
+}
+
+#[test]
+fn length_mismatch() {
+    with_ccid(|t| {
+        // dwLength announces 40 bytes, 3 arrive in a single packet
+        let mut message = xfr_block(0, 0, &[1, 2, 3]);
+        message[1..5].copy_from_slice(&40u32.to_le_bytes());
+        t.send(&message);
+        assert!(t.take_request().is_none());
+        assert_eq!(
+            t.ccid.take_last_reset_reason(),
+            Some(ResetReason::LengthMismatch)
+        );
+
+        // a long message must start with a full packet
+        let mut message = xfr_block(1, 0, &[1, 2, 3]);
+        message[1..5].copy_from_slice(&(PACKET_SIZE as u32).to_le_bytes());
+        t.send(&message);
+        assert_eq!(
+            t.ccid.take_last_reset_reason(),
+            Some(ResetReason::LengthMismatch)
+        );
+        assert_eq!(t.ccid.state(), State::Idle);
+
+        // the next command is not taken for the rest of the message
+        t.send(&xfr_block(2, 0, &[0x00, 0xca, 0x00, 0x00]));
+        assert_eq!(t.take_request().unwrap(), [0x00, 0xca, 0x00, 0x00]);
+    });
+}